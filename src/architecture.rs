@@ -0,0 +1,174 @@
+use crate::core_register::{
+    CortexMRegister,
+    RiscVRegister,
+};
+use crate::memory_map::Address;
+use crate::target::Target;
+
+/// Register values to call a flash algorithm function with.
+///
+/// The supported cores have 32-bit registers, so flash algorithms can only address the first
+/// 4 GiB. Flash above that is refused with `FlashError::AddressNotSupported`.
+#[derive(Debug, Clone)]
+pub struct FunctionCall {
+    pub pc: u32,
+    pub arguments: [Option<u32>; 4],
+    pub static_base: Option<u32>,
+    pub stack_pointer: Option<u32>,
+    /// Address of the breakpoint the function returns to.
+    pub breakpoint: u32,
+}
+
+/// State of the core after a flash algorithm function returned or was halted.
+#[derive(Debug, Clone)]
+pub struct CallState {
+    pub pc: u32,
+    pub return_address: u32,
+    pub stack_pointer: u32,
+    pub static_base: u32,
+    /// Number of the active exception, if the architecture reports one. 0 means none is active.
+    pub exception: Option<u32>,
+}
+
+/// How flash algorithm functions are called on one architecture.
+pub trait CallingConvention {
+    /// Instruction which halts the core, at most 4 bytes long.
+    ///
+    /// It is placed at the breakpoint address called functions return to.
+    fn breakpoint_instruction(&self) -> &'static [u8];
+
+    /// Set up the registers to make the call described by `call`.
+    fn write_call(&self, target: &Target, call: &FunctionCall);
+
+    /// Read the return value of a function which returned.
+    fn read_return_value(&self, target: &Target) -> u32;
+
+    /// Read the state of the core after a call.
+    fn read_state(&self, target: &Target) -> CallState;
+
+    /// Read the names and values of the registers which tell why the core faulted.
+    fn read_fault_status(&self, target: &Target) -> Vec<(&'static str, u32)>;
+}
+
+/// Calling convention of Thumb code on ARM Cortex-M cores.
+///
+/// Arguments are passed in r0 to r3, the static base in r9 and the function returns through LR,
+/// which has the Thumb bit set.
+pub struct CortexM;
+
+impl CortexM {
+    const CFSR: Address = 0xE000_ED28;
+    const HFSR: Address = 0xE000_ED2C;
+}
+
+impl CallingConvention for CortexM {
+    fn breakpoint_instruction(&self) -> &'static [u8] {
+        &[0x00, 0xBE] // BKPT #0
+    }
+
+    fn write_call(&self, target: &Target, call: &FunctionCall) {
+        let argument_registers = [CortexMRegister::R0, CortexMRegister::R1, CortexMRegister::R2, CortexMRegister::R3];
+        let mut registers = vec![(CortexMRegister::PC, call.pc)];
+        for (register, argument) in argument_registers.iter().zip(&call.arguments) {
+            if let Some(value) = argument {
+                registers.push((*register, *value));
+            }
+        }
+        if let Some(static_base) = call.static_base {
+            registers.push((CortexMRegister::R9, static_base));
+        }
+        if let Some(stack_pointer) = call.stack_pointer {
+            registers.push((CortexMRegister::SP, stack_pointer));
+        }
+        registers.push((CortexMRegister::LR, call.breakpoint | 1));
+        target.write_core_registers(registers.as_slice());
+    }
+
+    fn read_return_value(&self, target: &Target) -> u32 {
+        target.read_core_register(CortexMRegister::R0)
+    }
+
+    fn read_state(&self, target: &Target) -> CallState {
+        let registers = target.read_core_registers(&[
+            CortexMRegister::PC,
+            CortexMRegister::LR,
+            CortexMRegister::SP,
+            CortexMRegister::R9,
+            CortexMRegister::XPSR,
+        ]);
+        CallState {
+            pc: registers[0],
+            return_address: registers[1],
+            stack_pointer: registers[2],
+            static_base: registers[3],
+            exception: Some(registers[4] & CortexMRegister::IPSR_MASK),
+        }
+    }
+
+    fn read_fault_status(&self, target: &Target) -> Vec<(&'static str, u32)> {
+        vec![
+            ("CFSR", target.read_memory_block32(Self::CFSR, 1)[0]),
+            ("HFSR", target.read_memory_block32(Self::HFSR, 1)[0]),
+        ]
+    }
+}
+
+/// Calling convention of RISC-V cores.
+///
+/// Arguments are passed in a0 to a3, the static base in gp and the function returns through ra
+/// to an `ebreak` instruction. The debug module has to enter debug mode on `ebreak`
+/// (`dcsr.ebreakm`) for the call to halt there.
+pub struct RiscV;
+
+impl CallingConvention for RiscV {
+    fn breakpoint_instruction(&self) -> &'static [u8] {
+        &[0x73, 0x00, 0x10, 0x00] // ebreak
+    }
+
+    fn write_call(&self, target: &Target, call: &FunctionCall) {
+        let argument_registers = [RiscVRegister::A0, RiscVRegister::A1, RiscVRegister::A2, RiscVRegister::A3];
+        let mut registers = vec![(RiscVRegister::PC, call.pc)];
+        for (register, argument) in argument_registers.iter().zip(&call.arguments) {
+            if let Some(value) = argument {
+                registers.push((*register, *value));
+            }
+        }
+        if let Some(static_base) = call.static_base {
+            registers.push((RiscVRegister::GP, static_base));
+        }
+        if let Some(stack_pointer) = call.stack_pointer {
+            registers.push((RiscVRegister::SP, stack_pointer));
+        }
+        registers.push((RiscVRegister::RA, call.breakpoint));
+        target.write_core_registers(registers.as_slice());
+    }
+
+    fn read_return_value(&self, target: &Target) -> u32 {
+        target.read_core_register(RiscVRegister::A0)
+    }
+
+    fn read_state(&self, target: &Target) -> CallState {
+        let registers = target.read_core_registers(&[
+            RiscVRegister::PC,
+            RiscVRegister::RA,
+            RiscVRegister::SP,
+            RiscVRegister::GP,
+        ]);
+        CallState {
+            pc: registers[0],
+            return_address: registers[1],
+            stack_pointer: registers[2],
+            static_base: registers[3],
+            exception: None,
+        }
+    }
+
+    fn read_fault_status(&self, target: &Target) -> Vec<(&'static str, u32)> {
+        let registers = target.read_core_registers(&[RiscVRegister::MCAUSE, RiscVRegister::MEPC, RiscVRegister::MTVAL]);
+        vec![
+            ("mcause", registers[0]),
+            ("mepc", registers[1]),
+            ("mtval", registers[2]),
+        ]
+    }
+}
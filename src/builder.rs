@@ -11,43 +11,143 @@
 use crate::flash::{
     self,
     Flash,
+    FlashError,
+};
+use crate::cancel::CancellationToken;
+use crate::common::{
+    aligned_length,
+    checked_range,
+    crc32,
+    crc32_update,
+    ranges_overlap,
+    same,
+};
+use crate::journal::{
+    JournalError,
+    JournalOperation,
+    ProgrammingJournal,
+};
+use crate::memory_map::{
+    Address,
+    MemoryRegion,
+};
+use crate::page_cache::PageHashCache;
+use crate::retry::{
+    retry_page_operation,
+    RetryPolicy,
+};
+use crate::plan::{
+    PlannedSector,
+    RegionPlan,
+};
+use crate::progress::{
+    ProgressObserver,
+    ProgressPhase,
+    ProgressTracker,
+    ScaledProgress,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{
+    Duration,
+    Instant,
 };
-use crate::common::same;
 
 const PAGE_ESTIMATE_SIZE: u32 = 32;
-const PAGE_READ_WEIGHT: f32 = 0.3;
-const DATA_TRANSFER_B_PER_S: f32 = 40.0 * 1000.0; // ~40KB/s, depends on clock speed, theoretical limit for HID is 56,000 B/s
-
-// class ProgrammingInfo(object):
-//     def __init__(self):
-//         self.program_type = None                # Type of programming performed - FLASH_PAGE_ERASE or FLASH_CHIP_ERASE
-//         self.program_time = None                # Total programming time
-//         self.analyze_type = None                # Type of flash analysis performed - FLASH_ANALYSIS_CRC32 or FLASH_ANALYSIS_PARTIAL_PAGE_READ
-//         self.analyze_time = None                # Time to analyze flash contents
-//         self.program_byte_count = 0
-//         self.page_count = 0
-//         self.same_page_count = 0
+
+/// Type of programming performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramType {
+    ChipErase,
+    PageErase,
+}
+
+/// Type of flash analysis performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalysisType {
+    Crc32,
+    PartialPageRead,
+}
+
+/// Performance report of a programming run.
+#[derive(Debug, Clone, Default)]
+pub struct ProgrammingInfo {
+    pub program_type: Option<ProgramType>, // Type of programming performed
+    pub program_time: Duration, // Total programming time
+    pub analyze_type: Option<AnalysisType>, // Type of flash analysis performed
+    pub analyze_time: Duration, // Time to analyze flash contents
+    pub program_byte_count: usize,
+    pub page_count: usize,
+    pub same_page_count: usize,
+    pub erased_page_count: usize, // Number of pages erased with a page erase
+    pub erased_pages: Vec<Address>, // Addresses of the pages erased with a page erase
+    pub programmed_pages: Vec<Address>, // Addresses of the programmed pages
+    pub error_count: usize, // Number of failed page operations, including those which succeeded when retried
+    pub retried_pages: Vec<Address>, // Addresses of the pages which needed retries
+}
+
+impl ProgrammingInfo {
+    /// Bytes programmed per second over the whole programming time.
+    pub fn throughput(&self) -> f32 {
+        let seconds = self.program_time.as_secs_f32();
+        if seconds > 0.0 {
+            self.program_byte_count as f32 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Combine the report of another programming run into this one.
+    ///
+    /// If any run used a chip erase, the combined program type is a chip erase.
+    pub fn merge(&mut self, other: &ProgrammingInfo) {
+        self.program_type = match (self.program_type, other.program_type) {
+            (Some(ProgramType::ChipErase), _) | (_, Some(ProgramType::ChipErase)) => Some(ProgramType::ChipErase),
+            (a, b) => a.or(b),
+        };
+        self.analyze_type = self.analyze_type.or(other.analyze_type);
+        self.program_time += other.program_time;
+        self.analyze_time += other.analyze_time;
+        self.program_byte_count += other.program_byte_count;
+        self.page_count += other.page_count;
+        self.same_page_count += other.same_page_count;
+        self.erased_page_count += other.erased_page_count;
+        self.erased_pages.extend(&other.erased_pages);
+        self.programmed_pages.extend(&other.programmed_pages);
+        self.error_count += other.error_count;
+        self.retried_pages.extend(&other.retried_pages);
+    }
+}
 
 pub struct FlashPage {
-    address: u32,
+    address: Address,
     size: u32,
     data: Vec<u8>,
     erase_weight: f32,
     program_weight: f32,
+    data_transfer_rate: f32, // Bytes per second
     pub erased: Option<bool>,
     pub same: Option<bool>,
+    pub blank: Option<bool>,
+    flash_crc: Option<u32>, // CRC32 of the whole page in flash after programming, if known.
 }
 
 impl FlashPage {
-    pub fn new(address: u32, size: u32, data: Vec<u8>, erase_weight: f32, program_weight: f32) -> Self {
+    pub fn new(address: Address, size: u32, data: Vec<u8>, erase_weight: f32, program_weight: f32, data_transfer_rate: f32) -> Self {
         Self {
             address,
             size,
             data,
             erase_weight,
             program_weight,
+            data_transfer_rate,
             erased: None,
             same: None,
+            blank: None,
+            flash_crc: None,
         }
     }
 
@@ -55,34 +155,65 @@ impl FlashPage {
         self.data.extend(data);
     }
 
+    /// First address after the page.
+    pub fn end(&self) -> Address {
+        self.address.saturating_add(self.size as u64)
+    }
+
+    /// Program the data of the page, as a phrase if it does not fill the whole page.
+    fn program(&self, flash: &mut Flash) -> Result<(), FlashError> {
+        if self.data.len() < self.size as usize {
+            flash.program_phrase(self.address, self.data.as_slice())
+        } else {
+            flash.program_page(self.address, self.data.as_slice())
+        }
+    }
+
     /// Get time to verify a page.
     pub fn get_verify_weight(&self) -> f32 {
-        self.size as f32 / DATA_TRANSFER_B_PER_S
+        self.size as f32 / self.data_transfer_rate
     }
 
     /// Get time to program a page including the data transfer.
     fn get_program_weight(&self) -> f32 {
-        self.program_weight + self.data.len() as f32 / DATA_TRANSFER_B_PER_S
+        self.program_weight + self.data.len() as f32 / self.data_transfer_rate
     }
-}
 
-    // fn get_erase_program_weight(self):
-    //     """
-    //     Get time to erase and program a page including data transfer time
-    //     """
-    //     return self.erase_weight + self.program_weight + \
-    //         float(len(self.data)) / float(DATA_TRANSFER_B_PER_S)
+    /// Get the CRC32 of the page as it is in flash after erasing and programming it.
+    fn crc(&self, erased_byte_value: u8) -> u32 {
+        let mut data = self.data.clone();
+        data.resize(self.size as usize, erased_byte_value);
+        crc32(data.as_slice())
+    }
+
+    /// Get the CRC32 of the page as it is in flash after programming it over `old_data`.
+    ///
+    /// The part of the page after the data keeps its old content.
+    fn crc_over(&self, old_data: &[u8]) -> u32 {
+        let mut data = self.data.clone();
+        data.extend(&old_data[self.data.len()..]);
+        crc32(data.as_slice())
+    }
+
+    /// Get time to erase and program a page including the data transfer.
+    /// Get the time it takes to read the start of the page for the analysis.
+    fn get_estimate_weight(&self) -> f32 {
+        u32::min(PAGE_ESTIMATE_SIZE, self.data.len() as u32) as f32 / self.data_transfer_rate
+    }
 
-    
+    fn get_erase_program_weight(&self) -> f32 {
+        self.erase_weight + self.get_program_weight()
+    }
+}
 
-#[derive(Clone, Copy)]
-struct FlashOperation<'a> {
-    pub address: u32,
-    pub data: &'a [u8],
+#[derive(Clone)]
+struct FlashOperation {
+    pub address: Address,
+    pub data: Vec<u8>,
 }
 
-impl<'a> FlashOperation<'a> {
-    pub fn new(address: u32, data: &'a [u8]) -> Self {
+impl FlashOperation {
+    pub fn new(address: Address, data: Vec<u8>) -> Self {
         Self {
             address,
             data,
@@ -90,63 +221,202 @@ impl<'a> FlashOperation<'a> {
     }
 }
 
-pub struct FlashBuilder<'a> {
-    pub(crate) flash_start: u32,
-    flash_operations: Vec<FlashOperation<'a>>,
-    buffered_data_size: u32,
+pub struct FlashBuilder {
+    pub(crate) flash_start: Address,
+    flash_operations: Vec<FlashOperation>,
+    pub(crate) buffered_data_size: usize,
     flash: Flash,
     page_list: Vec<FlashPage>,
     enable_double_buffering: bool,
+    perf: ProgrammingInfo,
+    chip_erase_weight: f32,
+    page_erase_weight: f32,
+    protected_ranges: Vec<Range<Address>>,
+    page_cache: Option<Rc<RefCell<PageHashCache>>>,
+    cancellation_token: Option<CancellationToken>,
+    journal: Option<Rc<RefCell<ProgrammingJournal>>>,
+    retry_policy: RetryPolicy,
+    keep_unwritten: bool,
+    use_chip_erase: Option<bool>, // Decided by the analysis of the pages.
+}
+
+/// Reason programming stopped before all pages were written.
+enum Interrupted {
+    Cancelled,
+    Flash(FlashError),
+    Journal(JournalError),
+}
+
+impl From<FlashError> for Interrupted {
+    fn from(error: FlashError) -> Self {
+        Interrupted::Flash(error)
+    }
+}
+
+impl From<JournalError> for Interrupted {
+    fn from(error: JournalError) -> Self {
+        Interrupted::Journal(error)
+    }
 }
 
+#[derive(Debug)]
 pub enum FlashBuilderError {
-    AddressBeforeFlashStart(u32), // Contains faulty address.
-    DataOverlap(u32), // Contains faulty address.
-    InvalidFlashAddress(u32), // Contains faulty address.
+    AddressBeforeFlashStart(Address), // Contains faulty address.
+    AddressOverflow(Address), // Contains the address of data which runs past the end of the address space.
+    DataOverlap(Address), // Contains faulty address.
+    InvalidFlashAddress(Address), // Contains faulty address.
+    Cancelled(Box<ProgrammingInfo>), // Contains the report of what was done before cancelling.
+    ProtectedRange(Address), // Contains the address of the page which overlaps a protected range.
+    Journal(JournalError),
+    Flash(Range<Address>, FlashError), // Contains the address range of the region.
 }
 
-impl<'a> FlashBuilder<'a> {
+impl fmt::Display for FlashBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use FlashBuilderError::*;
+        match self {
+            AddressBeforeFlashStart(address) => write!(f, "address {:#010x} is before the start of the flash region", address),
+            AddressOverflow(address) => write!(f, "data at {:#010x} runs past the end of the address space", address),
+            DataOverlap(address) => write!(f, "data added twice for address {:#010x}", address),
+            InvalidFlashAddress(address) => write!(f, "address {:#010x} is not inside the flash region", address),
+            Cancelled(info) => write!(f, "programming was cancelled after programming {} pages", info.programmed_pages.len()),
+            ProtectedRange(address) => write!(f, "page at {:#010x} overlaps a protected range", address),
+            Journal(_) => write!(f, "could not record the progress of programming"),
+            Flash(region, _) => write!(f, "flash operation in region {:#010x}..{:#010x} failed", region.start, region.end),
+        }
+    }
+}
+
+impl std::error::Error for FlashBuilderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FlashBuilderError::Journal(e) => Some(e),
+            FlashBuilderError::Flash(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-    // TODO: Needed when we do advanced flash analysis.
-    // // Type of flash analysis
-    // FLASH_ANALYSIS_CRC32 = "CRC32"
-    // FLASH_ANALYSIS_PARTIAL_PAGE_READ = "PAGE_READ"
+impl FlashBuilder {
 
     pub fn new(flash: Flash) -> Self {
         let flash_start = flash.get_flash_info().rom_start;
         Self {
             flash,
-            flash_start: flash_start,
+            flash_start,
             flash_operations: vec![],
             buffered_data_size: 0,
             page_list: vec![],
             enable_double_buffering: false,
+            perf: ProgrammingInfo::default(),
+            chip_erase_weight: 0.0,
+            page_erase_weight: 0.0,
+            protected_ranges: vec![],
+            page_cache: None,
+            cancellation_token: None,
+            journal: None,
+            retry_policy: RetryPolicy::new(),
+            keep_unwritten: true,
+            use_chip_erase: None,
         }
     }
 
+    /// Set the address ranges which must not be erased or programmed.
+    ///
+    /// Programming fails if a page which would be written overlaps one of the ranges.
+    /// Chip erase is not used if the region contains one of the ranges.
+    pub fn set_protected_ranges(&mut self, ranges: Vec<Range<Address>>) {
+        self.protected_ranges = ranges;
+    }
+
+    /// Use a host side cache of page checksums to find unchanged pages without reading the target.
+    pub fn set_page_cache(&mut self, page_cache: Rc<RefCell<PageHashCache>>) {
+        self.page_cache = Some(page_cache);
+    }
+
+    /// Allow cancelling programming with `token`.
+    ///
+    /// When cancelled, programming stops after the current page operation and `program` returns
+    /// `FlashBuilderError::Cancelled` with the pages erased and programmed so far.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = Some(token);
+    }
+
+    /// Record the progress of programming in `journal` and resume the run it describes, if any.
+    pub fn set_journal(&mut self, journal: Rc<RefCell<ProgrammingJournal>>) {
+        self.journal = Some(journal);
+    }
+
+    /// Retry failed page operations according to `policy`.
+    ///
+    /// Pages which needed retries are listed in the returned `ProgrammingInfo`. By default
+    /// failed operations are not retried.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Decide how gaps in the data of a page are filled.
+    ///
+    /// Partial pages are padded up to the minimum program length of the flash algorithm. If
+    /// `keep` is set, which is the default, gaps keep the current content of the flash, otherwise
+    /// they are filled with the erased value. A partial page which has to be erased is then
+    /// programmed as a whole page, so the rest of it keeps its content as well.
+    pub fn set_keep_unwritten(&mut self, keep: bool) {
+        self.keep_unwritten = keep;
+    }
+
+    /// Uninit the flash algorithm and restore the target after a failed analysis.
+    pub(crate) fn cleanup(&mut self) -> Result<(), FlashBuilderError> {
+        self.flash.cleanup().map_err(|e| self.flash_error(e))
+    }
+
+    /// The region the data is programmed to.
+    pub(crate) fn region(&self) -> &MemoryRegion {
+        &self.flash.region
+    }
+
+    /// Add the data of this builder to `builder`, which has no data yet.
+    pub(crate) fn copy_data(&self, builder: &mut FlashBuilder) {
+        builder.flash_operations = self.flash_operations.clone();
+        builder.buffered_data_size = self.buffered_data_size;
+    }
+
+    /// Continue the checksum `crc` with the addresses and data to be programmed.
+    pub(crate) fn data_crc(&self, crc: u32) -> u32 {
+        self.flash_operations.iter().fold(crc, |crc, operation| {
+            crc32_update(crc32_update(crc, &operation.address.to_le_bytes()), operation.data.as_slice())
+        })
+    }
+
     /// Add a block of data to be programmed
     ///
     /// Note - programming does not start until the method
     /// program is called.
-    pub fn add_data(&mut self, address: u32, data: &'a [u8]) -> Result<(), FlashBuilderError> {
+    pub fn add_data(&mut self, address: Address, data: &[u8]) -> Result<(), FlashBuilderError> {
         // Sanity check
+        checked_range(address, data.len() as u64).ok_or(FlashBuilderError::AddressOverflow(address))?;
         if address >= self.flash_start {
             // Add operation to sorted list
-            match self.flash_operations.binary_search_by_key(&address, |&v| v.address) {
-                Ok(_) => { /* TODO: Return error as this should never happen (we would have double data for an address) */ },
-                Err(position) => self.flash_operations.insert(position, FlashOperation::new(address, data))
+            let position = match self.flash_operations.binary_search_by_key(&address, |v| v.address) {
+                Ok(_) => return Err(FlashBuilderError::DataOverlap(address)),
+                Err(position) => position,
+            };
+
+            // Cannot overflow, all operations were checked when they were added.
+            let end = address + data.len() as u64;
+            if let Some(previous) = position.checked_sub(1).map(|i| &self.flash_operations[i]) {
+                if previous.address + previous.data.len() as u64 > address {
+                    return Err(FlashBuilderError::DataOverlap(address));
+                }
             }
-            self.buffered_data_size += data.len() as u32;
-
-            let mut previous_operation: Option<FlashOperation> = None;
-            for operation in self.flash_operations {
-                if let Some(previous) = previous_operation {
-                    if previous.address + previous.data.len() as u32 > operation.address {
-                        return Err(FlashBuilderError::DataOverlap(operation.address));
-                    }
+            if let Some(next) = self.flash_operations.get(position) {
+                if end > next.address {
+                    return Err(FlashBuilderError::DataOverlap(address));
                 }
-                previous_operation = Some(operation);
             }
+
+            self.flash_operations.insert(position, FlashOperation::new(address, data.to_vec()));
+            self.buffered_data_size += data.len();
             Ok(())
         } else {
             Err(FlashBuilderError::AddressBeforeFlashStart(address))
@@ -156,8 +426,177 @@ impl<'a> FlashBuilder<'a> {
     /// Determine fastest method of flashing and then run flash programming.
     ///
     /// Data must have already been added with add_data
-    /// TODO: Not sure if this works as intended ...
-    pub fn program(self, chip_erase: bool, smart_flash: bool) -> Result<(), FlashBuilderError> {
+    /// `chip_erase` forces chip erase with `Some(true)` or page erase with `Some(false)`. With
+    /// `None` the faster method is chosen.
+    /// Returns a report of what was done and how long it took.
+    /// Progress of the erase and program operations is reported to `progress`. The pages are
+    /// analyzed first unless `analyze_data` was called before.
+    pub fn program(mut self, chip_erase: Option<bool>, smart_flash: bool, progress: &mut dyn ProgressObserver) -> Result<ProgrammingInfo, FlashBuilderError> {
+        let chip_erase = match self.use_chip_erase {
+            Some(chip_erase) => chip_erase,
+            // The share of the analysis is unknown here, so it is reported at the start.
+            None => self.analyze_data(chip_erase, smart_flash, &mut ScaledProgress::new(progress, 0.0, 0.0))?,
+        };
+        let program_start = Instant::now();
+
+        let result = if chip_erase {
+            if self.flash.is_double_buffering_supported && self.enable_double_buffering {
+                // TODO: Implement double buffering (for now it's disabled so not erasing here is ok as this if never triggers)
+                // self._chip_erase_program_double_buffer()
+                Ok(())
+            } else {
+                self.chip_erase_program(progress)
+            }
+        }
+        else {
+            if self.flash.is_double_buffering_supported && self.enable_double_buffering {
+                // TODO: Implement double buffering (for now it's disabled so not erasing here is ok as this if never triggers)
+                // self._page_erase_program_double_buffer()
+                Ok(())
+            } else {
+                self.page_erase_program(progress)
+            }
+        };
+
+        // The interrupted run is detected by the cache itself, so only record complete runs.
+        if result.is_ok() {
+            self.update_page_cache(chip_erase);
+        }
+
+        // Cleanup flash algo and reset target after programming.
+        let cleanup = self.flash.cleanup();
+        // TODO: Reset target at a different location.
+        // self.flash.target.reset_stop_on_reset();
+
+        self.perf.page_count = self.page_list.len();
+        self.perf.same_page_count = self.page_list.iter().filter(|page| page.same == Some(true)).count();
+        self.perf.program_time += program_start.elapsed();
+
+        match result {
+            Err(Interrupted::Cancelled) => return Err(FlashBuilderError::Cancelled(Box::new(self.perf))),
+            Err(Interrupted::Journal(e)) => return Err(FlashBuilderError::Journal(e)),
+            Err(Interrupted::Flash(e)) => return Err(self.flash_error(e)),
+            Ok(()) => (),
+        }
+        cleanup.map_err(|e| self.flash_error(e))?;
+
+        progress.progress(ProgressPhase::Program, 1.0);
+
+        Ok(self.perf)
+    }
+
+    /// Build the pages and decide how to program them.
+    ///
+    /// The reads of the analysis are reported to `progress`. Returns true if chip erase is to be used.
+    pub fn analyze_data(&mut self, chip_erase: Option<bool>, smart_flash: bool, progress: &mut dyn ProgressObserver) -> Result<bool, FlashBuilderError> {
+        let analysis_start = Instant::now();
+
+        if self.page_list.is_empty() {
+            self.build_pages()?;
+        }
+
+        progress.progress(ProgressPhase::Analyze, 0.0);
+        let journaled_plan = self.journal
+            .as_ref()
+            .and_then(|journal| journal.borrow().region_plan(self.flash.region.start).cloned());
+        let chip_erase = if let Some(plan) = journaled_plan {
+            // Resume an interrupted run with the decisions it made instead of analyzing the flash again.
+            self.apply_plan(&plan);
+            plan.chip_erase
+        } else {
+            self.validate_page_cache().map_err(|e| self.flash_error(e))?;
+            let chip_erase = self.analyze(chip_erase, smart_flash, progress);
+            if let Some(journal) = &self.journal {
+                journal.borrow_mut().record_plan(self.region_plan(chip_erase)).map_err(FlashBuilderError::Journal)?;
+            }
+            chip_erase
+        };
+        progress.progress(ProgressPhase::Analyze, 1.0);
+
+        self.use_chip_erase = Some(chip_erase);
+        self.perf.program_time = analysis_start.elapsed();
+        Ok(chip_erase)
+    }
+
+    /// Estimated time of analyzing the pages.
+    pub(crate) fn estimated_analysis_time(&self) -> f32 {
+        self.page_list.iter().filter(|page| page.same.is_none()).map(|page| page.get_estimate_weight()).sum()
+    }
+
+    /// Estimated time of erasing and programming the pages.
+    ///
+    /// Until the pages are analyzed, every page is assumed to be erased and programmed.
+    pub(crate) fn estimated_program_time(&self) -> f32 {
+        match self.use_chip_erase {
+            Some(true) => self.chip_erase_weight,
+            Some(false) => self.page_erase_weight,
+            None => self.page_list.iter().map(|page| page.get_erase_program_weight()).sum(),
+        }
+    }
+
+    /// Determine what `program` would do without erasing or programming anything.
+    ///
+    /// The flash is still read to find out which pages differ from the new data. The flash
+    /// algorithm used to validate the page cache is cleaned up again before returning.
+    pub fn plan(&mut self, chip_erase: Option<bool>, smart_flash: bool) -> Result<RegionPlan, FlashBuilderError> {
+        self.build_pages()?;
+        let validation = self.validate_page_cache();
+        let cleanup = self.flash.cleanup();
+        validation.and(cleanup).map_err(|e| self.flash_error(e))?;
+        let chip_erase = self.analyze(chip_erase, smart_flash, &mut |_, _| ());
+        Ok(self.region_plan(chip_erase))
+    }
+
+    /// Attach the address range of the region to an error of the flash algorithm.
+    fn flash_error(&self, error: FlashError) -> FlashBuilderError {
+        FlashBuilderError::Flash(self.flash.region.start..self.flash.region.end(), error)
+    }
+
+    /// Describe the operations which follow from the analysis of the pages.
+    fn region_plan(&self, chip_erase: bool) -> RegionPlan {
+        let region = &self.flash.region;
+        let mut plan = RegionPlan::new(region.start, region.end(), chip_erase);
+        if chip_erase {
+            plan.erased_sectors.push(PlannedSector::new(region.start, region.length, false));
+            plan.estimated_time = self.chip_erase_weight;
+            for page in &self.page_list {
+                if page.erased == Some(false) {
+                    plan.programmed_pages.push(PlannedSector::new(page.address, page.data.len() as u64, false));
+                }
+            }
+        } else {
+            plan.estimated_time = self.page_erase_weight;
+            for page in &self.page_list {
+                // Pages that could not be compared up front are only written if they differ.
+                let unverified = page.same.is_none();
+                if page.same != Some(true) {
+                    plan.erased_sectors.push(PlannedSector::new(page.address, page.size as u64, unverified));
+                    plan.programmed_pages.push(PlannedSector::new(page.address, page.data.len() as u64, unverified));
+                }
+            }
+        }
+        plan
+    }
+
+    /// Mark the pages according to the plan of an earlier run.
+    fn apply_plan(&mut self, plan: &RegionPlan) {
+        self.chip_erase_weight = plan.estimated_time;
+        self.page_erase_weight = plan.estimated_time;
+        if plan.chip_erase {
+            self.compute_chip_erase_pages_and_weight();
+        } else {
+            for page in &mut self.page_list {
+                page.same = match plan.programmed_pages.iter().find(|planned| planned.address == page.address) {
+                    Some(planned) if planned.unverified => None,
+                    Some(_) => Some(false),
+                    None => Some(true),
+                };
+            }
+        }
+    }
+
+    /// Convert the list of flash operations into flash pages.
+    pub(crate) fn build_pages(&mut self) -> Result<(), FlashBuilderError> {
         // Assumptions
         // 1. Page erases must be on page boundaries ( page_erase_addr % page_size == 0 )
         // 2. Page erase can have a different size depending on location
@@ -168,35 +607,36 @@ impl<'a> FlashBuilder<'a> {
         // - nRF51       - UICR location far from flash (address 0x10001000)
         // - LPC1768     - Different sized pages
 
+        self.page_list.clear();
+
+        // With a usable page cache, gaps are padded with the erased value like the cached
+        // checksums and only read from the target for pages which are not in the cache.
+        let defer_reads = self.keep_unwritten
+            && self.page_cache.as_ref().is_some_and(|page_cache| !page_cache.borrow().needs_validation());
+        let keep_unwritten = self.keep_unwritten && !defer_reads;
+        let mut gaps = vec![];
+
         // Convert the list of flash operations into flash pages
         let mut program_byte_count = 0;
-        let mut flash_address = self.flash_operations[0].address;
-        let mut info = self.flash.get_page_info(flash_address).ok_or_else(|| FlashBuilderError::InvalidFlashAddress(flash_address))?;
-        let mut page_address = flash_address - (flash_address % info.size);
-        let mut current_page = FlashPage::new(page_address, info.size, vec![], info.erase_weight, info.program_weight);
-        self.page_list.push(current_page);
-        for flash_operation in self.flash_operations {
+        let data_transfer_rate = self.flash.get_data_transfer_rate();
+        for flash_operation in &self.flash_operations {
             let mut pos = 0;
             while pos < flash_operation.data.len() {
                 // Check if operation is in next page
-                flash_address = flash_operation.address + pos as u32;
-                if flash_address >= current_page.address + current_page.size {
-                    info = self.flash.get_page_info(flash_address).ok_or_else(|| FlashBuilderError::InvalidFlashAddress(flash_address))?;
-                    page_address = flash_address - (flash_address % info.size);
-                    current_page = FlashPage::new(page_address, info.size, vec![], info.erase_weight, info.program_weight);
-                    self.page_list.push(current_page);
+                let flash_address = flash_operation.address + pos as u64;
+                let in_current_page = self.page_list.last().is_some_and(|page| flash_address < page.end());
+                if !in_current_page {
+                    let info = self.flash.get_page_info(flash_address).ok_or(FlashBuilderError::InvalidFlashAddress(flash_address))?;
+                    let page_address = flash_address - (flash_address % info.size as u64);
+                    self.page_list.push(FlashPage::new(page_address, info.size, vec![], info.erase_weight, info.program_weight, data_transfer_rate));
                 }
+                let current_page = self.page_list.last_mut().expect("the page of the address was added");
 
                 // Fill the page gap if there is one
-                // TODO:
-                // let page_data_end = current_page.address + current_page.data.len() as u32;
-                // if flash_address != page_data_end {
-                //     let old_data = self.flash.target.read_memory_block8(page_data_end, flash_address - page_data_end);
-                //     current_page.data.extend(old_data);
-                // }
+                gaps.push(Self::fill_page(&self.flash, keep_unwritten, current_page, flash_address));
 
                 // Copy data to page and increment pos
-                let space_left_in_page = info.size - current_page.data.len() as u32;
+                let space_left_in_page = current_page.size - current_page.data.len() as u32;
                 let space_left_in_data = flash_operation.data.len() - pos;
                 let amount = usize::min(space_left_in_page as usize, space_left_in_data);
                 current_page.extend(&flash_operation.data[pos..pos + amount]);
@@ -207,181 +647,524 @@ impl<'a> FlashBuilder<'a> {
             }
         }
 
+        // Pad partial pages to the minimum program length.
+        for page in &mut self.page_list {
+            let min_length = self.flash.get_min_program_length(page.address).unwrap_or(page.size);
+            let length = aligned_length(page.data.len() as u32, min_length, page.size);
+            gaps.push(Self::fill_page(&self.flash, keep_unwritten, page, page.address + length as u64));
+        }
+
+        if defer_reads {
+            self.read_gaps(gaps);
+        }
+
+        // Apply the locking policy before anything is erased, so a refused page leaves the flash
+        // as it is and the analysis compares the flash with the data which is programmed.
+        let region = self.flash.region.start..self.flash.region.end();
+        for page in &mut self.page_list {
+            let data = self.flash.override_security_bits(page.address, page.data.as_slice())
+                .map_err(|e| FlashBuilderError::Flash(region.clone(), e))?;
+            if let Cow::Owned(data) = data {
+                page.data = data;
+            }
+        }
+
+        // Refuse to touch pages which contain protected data.
+        for page in &self.page_list {
+            let page_range = page.address..page.end();
+            if self.protected_ranges.iter().any(|range| ranges_overlap(range, &page_range)) {
+                return Err(FlashBuilderError::ProtectedRange(page.address));
+            }
+        }
+
+        self.perf.program_byte_count = program_byte_count;
+        Ok(())
+    }
+
+    /// Fill `page` from the end of its data up to `end`.
+    ///
+    /// The gap keeps the current content of the flash if `keep_unwritten` is set, otherwise it
+    /// is filled with the erased value. Returns the range of the gap.
+    fn fill_page(flash: &Flash, keep_unwritten: bool, page: &mut FlashPage, end: Address) -> Range<Address> {
+        let data_end = page.address + page.data.len() as u64;
+        if end <= data_end {
+            return data_end..data_end;
+        }
+        let length = (end - data_end) as usize;
+        if keep_unwritten {
+            page.extend(flash.target.read_memory_block8(data_end, length as u32).as_slice());
+        } else {
+            page.data.resize(page.data.len() + length, flash.region.erased_byte_value);
+        }
+        data_end..end
+    }
+
+    /// Replace the padding in `gaps` with the current content of the flash, except in pages
+    /// which the page cache knows to be unchanged.
+    ///
+    /// The flash of an unchanged page holds the data padded with the erased value, so the
+    /// padding already is its content.
+    fn read_gaps(&mut self, mut gaps: Vec<Range<Address>>) {
+        let page_cache = match &self.page_cache {
+            Some(page_cache) => page_cache.borrow(),
+            None => return,
+        };
+        let erased_byte_value = self.flash.region.erased_byte_value;
+        gaps.sort_unstable_by_key(|gap| gap.start);
+        let mut gaps = gaps.into_iter().filter(|gap| !gap.is_empty()).peekable();
+        for page in &mut self.page_list {
+            let crc = page.crc(erased_byte_value);
+            let unchanged = page_cache.is_unchanged(page.address, page.size, crc);
+            if unchanged {
+                page.same = Some(true);
+                page.flash_crc = Some(crc);
+            }
+            while let Some(gap) = gaps.next_if(|gap| gap.start < page.end()) {
+                if !unchanged {
+                    let offset = (gap.start - page.address) as usize;
+                    let data = self.flash.target.read_memory_block8(gap.start, (gap.end - gap.start) as u32);
+                    page.data[offset..offset + data.len()].copy_from_slice(data.as_slice());
+                }
+            }
+        }
+    }
+
+    /// Analyze the flash and decide whether to use chip erase.
+    ///
+    /// A chip erase forced by `chip_erase` is only used if it is possible.
+    /// Returns true if chip erase is to be used.
+    fn analyze(&mut self, chip_erase: Option<bool>, smart_flash: bool, progress: &mut dyn ProgressObserver) -> bool {
         // If smart flash was set to false then mark all pages
         // as requiring programming
         if !smart_flash {
             self.mark_all_pages_for_programming();
+        } else if let Some(page_cache) = &self.page_cache {
+            // Pages which were last programmed with the same data need no analysis.
+            let page_cache = page_cache.borrow();
+            let erased_byte_value = self.flash.region.erased_byte_value;
+            for page in &mut self.page_list {
+                let crc = page.crc(erased_byte_value);
+                if page.same.is_none() && page_cache.is_unchanged(page.address, page.size, crc) {
+                    page.same = Some(true);
+                    page.flash_crc = Some(crc);
+                }
+            }
         }
         
-        // If the flash algo doesn't support erase all, disable chip erase.
-        if !self.flash.is_erase_all_supported {
-            chip_erase = false;
-        }
-
-        let (chip_erase_count, chip_erase_program_time) = self.compute_chip_erase_pages_and_weight();
+        // Chip erase needs the flash algo to support erase all, and falls back to page erase
+        // if it would wipe a protected range. No estimate may override this.
+        let region_range = self.flash.region.start..self.flash.region.end();
+        let chip_erase_allowed = self.flash.is_erase_all_supported
+            && !self.protected_ranges.iter().any(|range| ranges_overlap(range, &region_range));
+        let mut chip_erase = if chip_erase_allowed { chip_erase } else { Some(false) };
+
+        let (_chip_erase_count, chip_erase_program_time) = self.compute_chip_erase_pages_and_weight();
+        self.chip_erase_weight = chip_erase_program_time;
         let page_erase_min_program_time = self.compute_page_erase_pages_weight_min();
 
         // If chip_erase hasn't been specified determine if chip erase is faster
         // than page erase regardless of contents
-        if !chip_erase && (chip_erase_program_time < page_erase_min_program_time) {
-            chip_erase = true;
+        if chip_erase.is_none() && (chip_erase_program_time < page_erase_min_program_time) {
+            chip_erase = Some(true);
         }
 
-        // TODO:
         // If chip erase isn't True then analyze the flash
-        // if !chip_erase {
-        //     analyze_start = time()
-        //     if self.flash.get_flash_info().crc_supported {
-        //         sector_erase_count, page_program_time = self._compute_page_erase_pages_and_weight_crc32(fast_verify)
-        //         self.perf.analyze_type = FlashBuilder.FLASH_ANALYSIS_CRC32
-        //     else {
-        //         sector_erase_count, page_program_time = self._compute_page_erase_pages_and_weight_sector_read()
-        //         self.perf.analyze_type = FlashBuilder.FLASH_ANALYSIS_PARTIAL_PAGE_READ
-        //     analyze_finish = time()
-        //     self.perf.analyze_time = analyze_finish - analyze_start
-        //     LOG.debug("Analyze time { %f" % (analyze_finish - analyze_start))
-        // }
-
-        // If chip erase hasn't been set then determine fastest method to program
-        // if !chip_erase {
-        //     chip_erase = chip_erase_program_time < page_program_time;
-        // }
-
-        if chip_erase {
-            if self.flash.is_double_buffering_supported && self.enable_double_buffering {
-                // TODO: Implement double buffering (for now it's disabled so not erasing here is ok as this if never triggers)
-                // self._chip_erase_program_double_buffer()
-            } else {
-                self.chip_erase_program();
+        if chip_erase != Some(true) {
+            let analyze_start = Instant::now();
+            // TODO: Use _compute_page_erase_pages_and_weight_crc32 once the analyzer is supported.
+            let (_page_erase_count, page_program_time) = self.compute_page_erase_pages_and_weight_sector_read(progress);
+            self.perf.analyze_type = Some(AnalysisType::PartialPageRead);
+            self.perf.analyze_time = analyze_start.elapsed();
+            self.page_erase_weight = page_program_time;
+
+            // If chip erase hasn't been set then determine fastest method to program
+            if chip_erase.is_none() {
+                chip_erase = Some(chip_erase_program_time < page_program_time);
             }
         }
-        else {
-            if self.flash.is_double_buffering_supported && self.enable_double_buffering {
-                // TODO: Implement double buffering (for now it's disabled so not erasing here is ok as this if never triggers)
-                // self._page_erase_program_double_buffer()
-            } else {
-                self.page_erase_program();
-            }
+
+        chip_erase == Some(true)
+    }
+
+    /// Check the cached page checksums of this region against the target if they are due for validation.
+    ///
+    /// The whole cache is cleared if a single page does not match.
+    fn validate_page_cache(&mut self) -> Result<(), FlashError> {
+        let page_cache = match &self.page_cache {
+            Some(page_cache) if page_cache.borrow().needs_validation() => page_cache.clone(),
+            _ => return Ok(()),
         };
 
-        // Cleanup flash algo and reset target after programming.
-        self.flash.cleanup();
-        // TODO: Reset target at a different location.
-        // self.flash.target.reset_stop_on_reset();
+        let region_range = self.flash.region.start..self.flash.region.end();
+        let (sectors, expected): (Vec<(Address, u32)>, Vec<u32>) = page_cache
+            .borrow()
+            .pages_in(region_range)
+            .map(|(address, page)| ((*address, page.size), page.crc))
+            .unzip();
+
+        let crcs = if self.flash.get_flash_info().crc_supported {
+            self.flash.init(flash::FlashOperation::Program)?;
+            let crcs = self.flash.compute_crcs(sectors.as_slice());
+            let uninit = self.flash.uninit();
+            let crcs = crcs?;
+            uninit?;
+            crcs
+        } else {
+            sectors
+                .iter()
+                .map(|&(address, size)| crc32(self.flash.target.read_memory_block8(address, size).as_slice()))
+                .collect()
+        };
 
+        if crcs != expected {
+            page_cache.borrow_mut().clear();
+        }
         Ok(())
     }
 
+    /// Record the checksums of all pages after programming them.
+    ///
+    /// Pages whose content in flash is not known are removed from the cache.
+    fn update_page_cache(&self, chip_erase: bool) {
+        if let Some(page_cache) = &self.page_cache {
+            let mut page_cache = page_cache.borrow_mut();
+            if chip_erase {
+                page_cache.invalidate(self.flash.region.start..self.flash.region.end());
+            }
+            let erased_byte_value = self.flash.region.erased_byte_value;
+            for page in &self.page_list {
+                // After a chip erase, the rest of every page is erased.
+                let crc = if chip_erase { Some(page.crc(erased_byte_value)) } else { page.flash_crc };
+                match crc {
+                    Some(crc) => page_cache.insert(page.address, page.size, crc),
+                    None => page_cache.invalidate(page.address..page.end()),
+                }
+            }
+        }
+    }
+
     fn mark_all_pages_for_programming(&mut self) {
-        for page in self.page_list {
+        for page in &mut self.page_list {
             page.erased = None;
             page.same = None;
+            page.blank = None;
         }
     }
 
     /// Compute the number of erased pages.
     ///
     /// Determine how many pages in the new data are already erased.
-    fn compute_chip_erase_pages_and_weight(&self) -> (u32, f32) {
+    fn compute_chip_erase_pages_and_weight(&mut self) -> (u32, f32) {
         let mut chip_erase_count: u32 = 0;
         let mut chip_erase_weight: f32 = self.flash.get_flash_info().erase_weight;
-        for page in self.page_list {
-            if let Some(erased) = page.erased {
-                if !erased {
-                    chip_erase_count += 1;
-                    chip_erase_weight += page.get_program_weight();
-                    page.erased = Some(self.flash.region.is_erased(page.data.as_slice()));
-                }
-            } else {
+        for page in &mut self.page_list {
+            if page.erased.is_none() {
                 page.erased = Some(self.flash.region.is_erased(page.data.as_slice()));
             }
+            if page.erased == Some(false) {
+                chip_erase_count += 1;
+                chip_erase_weight += page.get_program_weight();
+            }
         }
         (chip_erase_count, chip_erase_weight)
     }
 
     fn compute_page_erase_pages_weight_min(&self) -> f32 {
         let mut page_erase_min_weight = 0.0;
-        for page in self.page_list {
+        for page in &self.page_list {
             page_erase_min_weight += page.get_verify_weight();
         }
-        return page_erase_min_weight
+        page_erase_min_weight
+    }
+
+    /// Estimate how many pages are the same.
+    ///
+    /// Quickly estimate how many pages are the same by reading the first few bytes of every page.
+    /// These estimates are used by page_erase_program so it is recommended to call this before
+    /// beginning programming.
+    fn compute_page_erase_pages_and_weight_sector_read(&mut self, progress: &mut dyn ProgressObserver) -> (u32, f32) {
+        // Quickly estimate how many pages are the same
+        let mut tracker = ProgressTracker::new(self.estimated_analysis_time());
+        for page in &mut self.page_list {
+            // Analyze pages that haven't been analyzed yet
+            if page.same.is_none() {
+                let size = usize::min(PAGE_ESTIMATE_SIZE as usize, page.data.len());
+                let data = self.flash.target.read_memory_block8(page.address, size as u32);
+                if !same(data.as_slice(), &page.data[0..size]) {
+                    page.same = Some(false);
+                }
+                tracker.advance(progress, ProgressPhase::Analyze, page.get_estimate_weight());
+            }
+        }
+
+        // Put together page and time estimate
+        let mut page_erase_count = 0;
+        let mut page_erase_weight = 0.0;
+        for page in &self.page_list {
+            match page.same {
+                Some(false) => {
+                    page_erase_count += 1;
+                    page_erase_weight += page.get_erase_program_weight();
+                },
+                // Page is probably the same but must be read to confirm
+                None => page_erase_weight += page.get_verify_weight(),
+                // Page is confirmed to be the same so no programming weight
+                Some(true) => (),
+            }
+        }
+
+        (page_erase_count, page_erase_weight)
     }
 
     /// Program by first performing a chip erase.
-    fn chip_erase_program(&mut self) {
-        self.flash.init(flash::FlashOperation::Erase);
-        self.flash.erase_all();
-        self.flash.uninit();
+    ///
+    /// Operations which the journal records as completed are skipped. A page which was being
+    /// programmed when an earlier run was interrupted is verified and erased again if it differs.
+    fn chip_erase_program(&mut self, progress: &mut dyn ProgressObserver) -> Result<(), Interrupted> {
+        let token = self.cancellation_token.clone();
+        let is_cancelled = || token.as_ref().is_some_and(|token| token.is_cancelled());
+        let journal = self.journal.clone();
+        let is_completed = |operation| journal.as_ref().is_some_and(|journal| journal.borrow().is_completed(operation));
+        let in_flight = journal.as_ref().and_then(|journal| journal.borrow().in_flight());
+        let begin = |operation| journal.as_ref().map_or(Ok(()), |journal| journal.borrow_mut().begin(operation));
+        let complete = |operation| journal.as_ref().map_or(Ok(()), |journal| journal.borrow_mut().complete(operation));
+
+        let mut tracker = ProgressTracker::new(self.chip_erase_weight);
+        progress.progress(ProgressPhase::Erase, 0.0);
+
+        let erase = JournalOperation::ChipErase(self.flash.region.start);
+        if !is_completed(erase) {
+            if is_cancelled() { return Err(Interrupted::Cancelled); }
+            begin(erase)?;
+            self.flash.init(flash::FlashOperation::Erase)?;
+            self.flash.erase_all()?;
+            self.flash.uninit()?;
+            complete(erase)?;
+        }
+        self.perf.program_type = Some(ProgramType::ChipErase);
+        tracker.advance(progress, ProgressPhase::Erase, self.flash.get_flash_info().erase_weight);
         
-        self.flash.init(flash::FlashOperation::Program);
-        for page in self.page_list {
+        self.flash.init(flash::FlashOperation::Program)?;
+        for page in &self.page_list {
             if let Some(erased) = page.erased {
                 if !erased {
-                    self.flash.program_page(page.address, page.data.as_slice());
+                    let program = JournalOperation::PageProgram(page.address);
+                    if is_completed(program) {
+                        tracker.advance(progress, ProgressPhase::Program, page.get_program_weight());
+                        continue;
+                    }
+                    if is_cancelled() { return Err(Interrupted::Cancelled); }
+
+                    let mut page_retries = 0;
+                    if in_flight == Some(program) {
+                        let data = self.flash.target.read_memory_block8(page.address, page.data.len() as u32);
+                        if same(data.as_slice(), page.data.as_slice()) {
+                            complete(program)?;
+                            tracker.advance(progress, ProgressPhase::Program, page.get_program_weight());
+                            continue;
+                        }
+                        // The page was partially programmed, so it has to be erased again.
+                        self.flash.uninit()?;
+                        self.flash.init(flash::FlashOperation::Erase)?;
+                        page_retries += retry_page_operation(
+                            &mut self.flash,
+                            &self.retry_policy,
+                            &mut self.perf.error_count,
+                            flash::FlashOperation::Erase,
+                            page.address,
+                            |flash| flash.erase_page(page.address)
+                        )?;
+                        self.flash.uninit()?;
+                        self.flash.init(flash::FlashOperation::Program)?;
+                        self.perf.erased_page_count += 1;
+                        self.perf.erased_pages.push(page.address);
+                    }
+
+                    begin(program)?;
+                    page_retries += retry_page_operation(
+                        &mut self.flash,
+                        &self.retry_policy,
+                        &mut self.perf.error_count,
+                        flash::FlashOperation::Program,
+                        page.address,
+                        |flash| page.program(flash)
+                    )?;
+                    complete(program)?;
+                    self.perf.programmed_pages.push(page.address);
+                    if page_retries > 0 {
+                        self.perf.retried_pages.push(page.address);
+                    }
+                    tracker.advance(progress, ProgressPhase::Program, page.get_program_weight());
                 }
             }
         }
-        self.flash.uninit();
+        self.flash.uninit()?;
+        Ok(())
     }
 
     /// Program by performing sector erases.
-    fn page_erase_program(&self) {
-        for page in self.page_list {
-            // Read page data if unknown - after this page.same will be True or False
-            if let Some(same) = page.same {
-                // Program page if not the same
-                if !same {
-                    self.flash.init(flash::FlashOperation::Erase);
-                    self.flash.erase_page(page.address);
-                    self.flash.uninit();
-
-                    self.flash.init(flash::FlashOperation::Program);
-                    self.flash.program_page(page.address, page.data.as_slice());
-                    self.flash.uninit();
-                }
-            } else {
-                let data = self.flash.target.read_memory_block8(page.address, page.data.len());
-                page.same = Some(same(page.data.as_slice(), data));
+    ///
+    /// Pages that are known to be blank are programmed without erasing them first. If the region
+    /// allows it, the same goes for pages where the new data only programs additional bits.
+    /// Pages which the journal records as programmed are skipped without reading them back.
+    fn page_erase_program(&mut self, progress: &mut dyn ProgressObserver) -> Result<(), Interrupted> {
+        let token = self.cancellation_token.clone();
+        let is_cancelled = || token.as_ref().is_some_and(|token| token.is_cancelled());
+        let journal = self.journal.clone();
+        let is_completed = |operation| journal.as_ref().is_some_and(|journal| journal.borrow().is_completed(operation));
+        let in_flight = journal.as_ref().and_then(|journal| journal.borrow().in_flight());
+        let begin = |operation| journal.as_ref().map_or(Ok(()), |journal| journal.borrow_mut().begin(operation));
+        let complete = |operation| journal.as_ref().map_or(Ok(()), |journal| journal.borrow_mut().complete(operation));
+
+        self.perf.program_type = Some(ProgramType::PageErase);
+        let mut tracker = ProgressTracker::new(self.page_erase_weight);
+        let program_without_erase = self.flash.region.program_without_erase;
+        let erased_byte_value = self.flash.region.erased_byte_value;
+        for page in &mut self.page_list {
+            let erase = JournalOperation::PageErase(page.address);
+            let program = JournalOperation::PageProgram(page.address);
+            if is_completed(program) {
+                tracker.advance(progress, ProgressPhase::Program, page.get_erase_program_weight());
+                continue;
             }
-        }
-    }
-}
 
-    // def _compute_page_erase_pages_and_weight_sector_read(self):
-    //     """
-    //     Estimate how many pages are the same.
+            // The page the interrupted run was programming has to be verified.
+            let interrupted = in_flight == Some(program);
+
+            // Read page data if unknown - after this page.same will be True or False.
+            // The whole page is read, so the same data tells whether the page is blank.
+            let mut old_data = None;
+            if page.same.is_none() || interrupted {
+                let data = self.flash.target.read_memory_block8(page.address, page.size);
+                page.same = Some(same(page.data.as_slice(), &data[..page.data.len()]));
+                page.blank = Some(self.flash.region.is_erased(data.as_slice()));
+                if page.same == Some(true) {
+                    page.flash_crc = Some(crc32(data.as_slice()));
+                }
+                old_data = Some(data);
+                tracker.advance(progress, ProgressPhase::Verify, page.get_verify_weight());
+            }
 
-    //     Quickly estimate how many pages are the same.  These estimates are used
-    //     by page_erase_program so it is recommended to call this before beginning programming
-    //     This is done automatically by smart_program.
-    //     """
-    //     # Quickly estimate how many pages are the same
-    //     page_erase_count = 0
-    //     page_erase_weight = 0
-    //     for page in self.page_list:
-    //         # Analyze pages that haven't been analyzed yet
-    //         if page.same is None:
-    //             size = min(PAGE_ESTIMATE_SIZE, len(page.data))
-    //             data = self.flash.target.read_memory_block8(page.address, size)
-    //             page_same = same(data, page.data[0:size])
-    //             if page_same is False:
-    //                 page.same = False
+            // Program page if not the same
+            if let Some(false) = page.same {
+                let mut page_retries = 0;
+
+                // Don't program over a partially programmed page.
+                let mut flash_crc = None;
+                let program_over = !interrupted && program_without_erase && {
+                    let data = match old_data.take() {
+                        Some(data) => data,
+                        None => {
+                            let data = self.flash.target.read_memory_block8(page.address, page.size);
+                            page.blank = Some(self.flash.region.is_erased(data.as_slice()));
+                            data
+                        },
+                    };
+                    let program_over = self.flash.region.can_program_over(&data[..page.data.len()], page.data.as_slice());
+                    if program_over {
+                        flash_crc = Some(page.crc_over(data.as_slice()));
+                    }
+                    old_data = Some(data);
+                    program_over
+                };
 
-    //     # Put together page and time estimate
-    //     for page in self.page_list:
-    //         if page.same is False:
-    //             page_erase_count += 1
-    //             page_erase_weight += page.get_erase_program_weight()
-    //         elif page.same is None:
-    //             # Page is probably the same but must be read to confirm
-    //             page_erase_weight += page.get_verify_weight()
-    //         elif page.same is True:
-    //             # Page is confirmed to be the same so no programming weight
-    //             pass
+                // The interrupted run erased this page and did not start programming it yet.
+                if is_completed(erase) && !interrupted {
+                    page.blank = Some(true);
+                }
 
-    //     self.page_erase_count = page_erase_count
-    //     self.page_erase_weight = page_erase_weight
-    //     return page_erase_count, page_erase_weight
+                if !program_over && page.blank != Some(true) {
+                    self.flash.init(flash::FlashOperation::Erase)?;
+                    let keep_remainder = self.keep_unwritten && page.data.len() < page.size as usize;
+                    if page.blank.is_none() {
+                        if self.flash.has_blank_check() {
+                            // A failed blank check only costs us an unnecessary erase.
+                            let range = page.address..page.end();
+                            page.blank = Some(self.flash.blank_check(range).unwrap_or(false));
+                        } else if keep_remainder || page.get_verify_weight() < page.erase_weight {
+                            // Reading the page back is cheaper than erasing it, or needed anyway.
+                            let data = self.flash.target.read_memory_block8(page.address, page.size);
+                            page.blank = Some(self.flash.region.is_erased(data.as_slice()));
+                            old_data = Some(data);
+                        } else {
+                            // Erasing the page is cheaper than reading it back.
+                            page.blank = Some(false);
+                        }
+                    }
+                    if page.blank == Some(false) {
+                        // The erase wipes the whole page, so the rest of it is written back.
+                        if keep_remainder {
+                            let data = match old_data.take() {
+                                Some(data) => data,
+                                None => self.flash.target.read_memory_block8(page.address, page.size),
+                            };
+                            let length = page.data.len();
+                            page.extend(&data[length..]);
+                        }
+                        if is_cancelled() { return Err(Interrupted::Cancelled); }
+                        begin(erase)?;
+                        page_retries += retry_page_operation(
+                            &mut self.flash,
+                            &self.retry_policy,
+                            &mut self.perf.error_count,
+                            flash::FlashOperation::Erase,
+                            page.address,
+                            |flash| flash.erase_page(page.address)
+                        )?;
+                        complete(erase)?;
+                        self.perf.erased_page_count += 1;
+                        self.perf.erased_pages.push(page.address);
+                    }
+                    self.flash.uninit()?;
+                }
+                tracker.advance(progress, ProgressPhase::Erase, page.erase_weight);
+
+                // A retried program erases the page, so the old content kept by programming over
+                // it is written back then.
+                let mut remainder = match old_data {
+                    Some(ref data) if program_over && self.keep_unwritten => Some(data[page.data.len()..].to_vec()),
+                    _ => None,
+                };
+                let mut attempted = false;
+
+                if is_cancelled() { return Err(Interrupted::Cancelled); }
+                begin(program)?;
+                self.flash.init(flash::FlashOperation::Program)?;
+                page_retries += retry_page_operation(
+                    &mut self.flash,
+                    &self.retry_policy,
+                    &mut self.perf.error_count,
+                    flash::FlashOperation::Program,
+                    page.address,
+                    |flash| {
+                        if attempted {
+                            if let Some(data) = remainder.take() {
+                                page.extend(&data);
+                            }
+                        }
+                        attempted = true;
+                        page.program(flash)
+                    }
+                )?;
+                self.flash.uninit()?;
+                complete(program)?;
+                self.perf.programmed_pages.push(page.address);
+                if page_retries > 0 {
+                    self.perf.retried_pages.push(page.address);
+                }
+                tracker.advance(progress, ProgressPhase::Program, page.get_program_weight());
+
+                // The page now holds our data, so it must be erased before it is written again.
+                page.blank = Some(false);
+                // A retried program erased the page and wrote the old content back with the data.
+                page.flash_crc = if program_over && page_retries == 0 { flash_crc } else { Some(page.crc(erased_byte_value)) };
+            } else if interrupted {
+                complete(program)?;
+            }
+        }
+        Ok(())
+    }
+}
 
     // def _compute_page_erase_pages_and_weight_crc32(self, assume_estimate_correct=False):
     //     """
@@ -621,3 +1404,153 @@ impl<'a> FlashBuilder<'a> {
     //     LOG.debug("Actual page erase count: %i", actual_page_erase_count)
 
     //     return FlashBuilder.FLASH_PAGE_ERASE
+
+#[test]
+fn analyze_does_not_chip_erase_protected_ranges() {
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::target::Target;
+
+    // Erased data is cheaper to write with a chip erase than to verify page by page.
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x20000, 0x400, None);
+    let mut builder = FlashBuilder::new(Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new()));
+    builder.add_data(0x8000, &[0xFF; 0x10000]).unwrap();
+    builder.build_pages().unwrap();
+    assert!(builder.analyze(None, true, &mut |_, _| ()));
+
+    let vector_table = 0x0..0x1000;
+    builder.set_protected_ranges(vec![vector_table]);
+    builder.build_pages().unwrap();
+    assert!(!builder.analyze(None, true, &mut |_, _| ()));
+    assert!(!builder.analyze(Some(true), true, &mut |_, _| ()));
+}
+
+#[test]
+fn analyze_respects_forced_page_erase_and_missing_erase_all() {
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::target::Target;
+
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x20000, 0x400, None);
+    let mut builder = FlashBuilder::new(Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new()));
+    builder.add_data(0x8000, &[0xFF; 0x10000]).unwrap();
+    builder.build_pages().unwrap();
+    assert!(!builder.analyze(Some(false), true, &mut |_, _| ()));
+
+    builder.flash.is_erase_all_supported = false;
+    builder.build_pages().unwrap();
+    assert!(!builder.analyze(None, true, &mut |_, _| ()));
+}
+
+#[test]
+fn partial_pages_are_padded_and_written_back() {
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::target::Target;
+
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    let mut algorithm = FlashAlgorithm::new();
+    algorithm.set_min_program_length(0x100);
+    let new_builder = || {
+        let mut builder = FlashBuilder::new(Flash::new(Rc::new(Target::new()), region.clone(), algorithm.clone()));
+        builder.add_data(0x0, &[0x55; 0x10]).unwrap();
+        builder.add_data(0x230, &[0x55; 0x10]).unwrap();
+        builder
+    };
+
+    // Gaps keep the flash content, which reads as zeros here.
+    let mut builder = new_builder();
+    builder.build_pages().unwrap();
+    let data = &builder.page_list[0].data;
+    assert_eq!(data.len(), 0x300);
+    assert!(data[0x10..0x230].iter().all(|&byte| byte == 0x00));
+    assert!(data[0x240..].iter().all(|&byte| byte == 0x00));
+
+    // The page has to be erased, so all of it is programmed to keep the rest of the page.
+    assert!(!builder.analyze(Some(false), true, &mut |_, _| ()));
+    assert!(builder.page_erase_program(&mut |_, _| ()).is_ok());
+    assert_eq!(builder.page_list[0].data.len(), 0x400);
+
+    let mut builder = new_builder();
+    builder.set_keep_unwritten(false);
+    builder.build_pages().unwrap();
+    let data = &builder.page_list[0].data;
+    assert_eq!(data.len(), 0x300);
+    assert!(data[0x10..0x230].iter().all(|&byte| byte == 0xFF));
+    assert!(data[0x240..].iter().all(|&byte| byte == 0xFF));
+}
+
+#[test]
+fn cached_pages_are_not_read_for_their_gaps() {
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::target::Target;
+
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    let mut algorithm = FlashAlgorithm::new();
+    algorithm.set_min_program_length(0x100);
+    let mut builder = FlashBuilder::new(Flash::new(Rc::new(Target::new()), region, algorithm));
+    builder.add_data(0x0, &[0x55; 0x10]).unwrap();
+    builder.add_data(0x400, &[0x55; 0x10]).unwrap();
+
+    // The first page was programmed with the same data before.
+    let mut cached = vec![0x55; 0x10];
+    cached.resize(0x400, 0xFF);
+    let mut page_cache = PageHashCache::new("device");
+    page_cache.insert(0x0, 0x400, crc32(cached.as_slice()));
+    builder.set_page_cache(Rc::new(RefCell::new(page_cache)));
+    builder.build_pages().unwrap();
+
+    // The gap of the cached page is what the cache says the flash holds, the other one is read.
+    assert_eq!(builder.page_list[0].same, Some(true));
+    assert!(builder.page_list[0].data[0x10..].iter().all(|&byte| byte == 0xFF));
+    assert_eq!(builder.page_list[1].same, None);
+    assert!(builder.page_list[1].data[0x10..].iter().all(|&byte| byte == 0x00));
+}
+
+#[test]
+fn locking_values_are_handled_before_programming() {
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::security::{
+        KinetisSecurityBits,
+        LockingPolicy,
+    };
+    use crate::target::Target;
+
+    let new_builder = |policy| {
+        let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+        let mut flash = Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new());
+        flash.set_security_bits(Box::new(KinetisSecurityBits));
+        flash.set_locking_policy(policy);
+        let mut builder = FlashBuilder::new(flash);
+        // FSEC = 0xFF secures the device.
+        builder.add_data(0x400, &[0xFF; 0x400]).unwrap();
+        builder
+    };
+
+    let mut builder = new_builder(LockingPolicy::Refuse);
+    match builder.build_pages() {
+        Err(FlashBuilderError::Flash(_, FlashError::LockingValue(0x40C))) => (),
+        _ => panic!("the locking value was not refused"),
+    }
+
+    let mut builder = new_builder(LockingPolicy::Unlock);
+    builder.build_pages().unwrap();
+    assert_eq!(builder.page_list[0].data[0xC], 0xFE);
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    BufReader,
+    BufWriter,
+    ErrorKind,
+};
+use std::path::Path;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use crate::cost::CalibratedCostModel;
+use crate::memory_map::Address;
+
+/// Calibrated cost model of one flash region on one target, programmed through one probe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationEntry {
+    pub target: String,
+    pub probe: String,
+    pub region_start: Address,
+    pub model: CalibratedCostModel,
+}
+
+/// Cache of calibrated cost models which persists across sessions.
+///
+/// The database is stored as JSON, so it can be inspected with any text editor. Files with a
+/// different version are discarded on load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationDatabase {
+    pub version: u32,
+    entries: Vec<CalibrationEntry>,
+}
+
+impl Default for CalibrationDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalibrationError::Io(_) => write!(f, "could not access the calibration database"),
+            CalibrationError::Format(_) => write!(f, "the calibration database is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CalibrationError::Io(e) => Some(e),
+            CalibrationError::Format(e) => Some(e),
+        }
+    }
+}
+
+impl CalibrationDatabase {
+    pub const VERSION: u32 = 1;
+
+    pub fn new() -> Self {
+        Self {
+            version: Self::VERSION,
+            entries: vec![],
+        }
+    }
+
+    /// Load the database from `path`.
+    ///
+    /// Returns an empty database if the file does not exist or has a different version.
+    pub fn load(path: &Path) -> Result<Self, CalibrationError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(CalibrationError::Io(e)),
+        };
+        let database: Self = serde_json::from_reader(BufReader::new(file)).map_err(CalibrationError::Format)?;
+        if database.version != Self::VERSION {
+            return Ok(Self::new());
+        }
+        Ok(database)
+    }
+
+    /// Store the database at `path`.
+    pub fn save(&self, path: &Path) -> Result<(), CalibrationError> {
+        let file = File::create(path).map_err(CalibrationError::Io)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).map_err(CalibrationError::Format)
+    }
+
+    /// All calibrated cost models in the database.
+    pub fn entries(&self) -> &[CalibrationEntry] {
+        &self.entries
+    }
+
+    /// Get the calibrated cost model of a region.
+    pub fn get(&self, target: &str, probe: &str, region_start: Address) -> Option<&CalibratedCostModel> {
+        self.entries
+            .iter()
+            .find(|e| e.target == target && e.probe == probe && e.region_start == region_start)
+            .map(|e| &e.model)
+    }
+
+    /// Insert or replace the calibrated cost model of a region.
+    pub fn update(&mut self, target: &str, probe: &str, region_start: Address, model: CalibratedCostModel) {
+        let existing = self.entries
+            .iter_mut()
+            .find(|e| e.target == target && e.probe == probe && e.region_start == region_start);
+        if let Some(entry) = existing {
+            entry.model = model;
+        } else {
+            self.entries.push(CalibrationEntry {
+                target: target.to_owned(),
+                probe: probe.to_owned(),
+                region_start,
+                model,
+            });
+        }
+    }
+
+    /// Forget all calibrations of a target and probe combination.
+    pub fn remove(&mut self, target: &str, probe: &str) {
+        self.entries.retain(|e| !(e.target == target && e.probe == probe));
+    }
+
+    /// Forget all calibrations.
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[test]
+fn calibration_database_round_trip() {
+    use crate::cost::{
+        CostModel,
+        CostOperation,
+    };
+    use std::time::Duration;
+
+    let mut model = CalibratedCostModel::new();
+    model.record(CostOperation::PageErase, 1024, Duration::from_millis(20));
+
+    let mut database = CalibrationDatabase::new();
+    database.update("target", "probe", 0x0, CalibratedCostModel::new());
+    database.update("target", "probe", 0x0, model);
+    database.update("target", "probe", 0x8000, CalibratedCostModel::new());
+    assert_eq!(database.entries().len(), 2);
+
+    let path = std::env::temp_dir().join(format!("flash-rs-calibration-{}.json", std::process::id()));
+    database.save(&path).unwrap();
+    let loaded = CalibrationDatabase::load(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.entries().len(), 2);
+    let model = loaded.get("target", "probe", 0x0).unwrap();
+    assert!((model.page_erase_weight(0x0, 1024) - 0.020).abs() < 1e-6);
+    assert!(loaded.get("target", "other probe", 0x0).is_none());
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+
+/// Token to cancel programming from another thread.
+///
+/// Clones share the same state, so one clone can be handed to the FlashLoader or FlashBuilder
+/// while another is used to cancel. Cancellation takes effect between page operations.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request programming to stop after the current flash algorithm call.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -1,3 +1,6 @@
+use std::ops::Range;
+use crate::memory_map::Address;
+
 pub fn same(d1: &[u8], d2: &[u8]) -> bool {
     if d1.len() != d2.len() {
         return false;
@@ -8,4 +11,66 @@ pub fn same(d1: &[u8], d2: &[u8]) -> bool {
         }
     }
     true
-}
\ No newline at end of file
+}
+
+/// Check if two address ranges share at least one address.
+pub fn ranges_overlap(a: &Range<Address>, b: &Range<Address>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The range of `length` bytes starting at `start`, or `None` if it wraps around the address space.
+pub fn checked_range(start: Address, length: u64) -> Option<Range<Address>> {
+    start.checked_add(length).map(|end| start..end)
+}
+
+/// Round `length` up to a multiple of `unit`, but not beyond `limit`.
+///
+/// A `unit` of 0 leaves `length` as it is.
+pub fn aligned_length(length: u32, unit: u32, limit: u32) -> u32 {
+    if unit == 0 {
+        return length;
+    }
+    let aligned = (length as u64).div_ceil(unit as u64) * unit as u64;
+    u64::min(aligned, limit as u64) as u32
+}
+
+/// Compute the CRC32 of `data`, the same checksum the flash analyzer computes on the target.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue the CRC32 `crc` of some data with the bytes in `data`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn crc32_matches_reference() {
+    assert_eq!(crc32(b""), 0x0000_0000);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+}
+
+#[test]
+fn checked_range_detects_wrapping() {
+    assert_eq!(checked_range(0x1_0000_0000, 0x100), Some(0x1_0000_0000..0x1_0000_0100));
+    assert_eq!(checked_range(0xFFFF_FFFF_FFFF_FF00, 0x100), None);
+}
+
+#[test]
+fn aligned_length_rounds_up_to_unit() {
+    assert_eq!(aligned_length(0, 8, 256), 0);
+    assert_eq!(aligned_length(1, 8, 256), 8);
+    assert_eq!(aligned_length(16, 8, 256), 16);
+    assert_eq!(aligned_length(250, 16, 256), 256);
+    assert_eq!(aligned_length(100, 1024, 256), 256);
+    assert_eq!(aligned_length(5, 0, 256), 5);
+}
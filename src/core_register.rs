@@ -0,0 +1,121 @@
+/// A core register of one architecture.
+///
+/// Every architecture has its own register enum, so registers of different architectures can not
+/// be mixed up and a misspelled register is a compile error.
+pub trait CoreRegister: Copy {
+    /// Number which selects the register in the debug interface of the core.
+    fn id(self) -> u16;
+}
+
+/// Core registers of ARM Cortex-M cores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CortexMRegister {
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9, // Static base
+    R10,
+    R11,
+    R12,
+    SP,
+    LR,
+    PC, // Debug return address
+    XPSR,
+    MSP,
+    PSP,
+}
+
+impl CortexMRegister {
+    /// Mask of the exception number (IPSR) in XPSR.
+    pub const IPSR_MASK: u32 = 0x1FF;
+}
+
+impl CoreRegister for CortexMRegister {
+    /// REGSEL value of the register in DCRSR.
+    fn id(self) -> u16 {
+        use CortexMRegister::*;
+        match self {
+            R0 => 0,
+            R1 => 1,
+            R2 => 2,
+            R3 => 3,
+            R4 => 4,
+            R5 => 5,
+            R6 => 6,
+            R7 => 7,
+            R8 => 8,
+            R9 => 9,
+            R10 => 10,
+            R11 => 11,
+            R12 => 12,
+            SP => 13,
+            LR => 14,
+            PC => 15,
+            XPSR => 16,
+            MSP => 17,
+            PSP => 18,
+        }
+    }
+}
+
+/// Core registers of RISC-V cores, by their ABI names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RiscVRegister {
+    Zero,
+    RA,
+    SP,
+    GP,
+    TP,
+    T0,
+    T1,
+    T2,
+    S0, // Frame pointer
+    S1,
+    A0,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    S2,
+    S3,
+    S4,
+    S5,
+    S6,
+    S7,
+    S8,
+    S9,
+    S10,
+    S11,
+    T3,
+    T4,
+    T5,
+    T6,
+    PC, // Debug PC (dpc)
+    MEPC,
+    MCAUSE,
+    MTVAL,
+}
+
+impl CoreRegister for RiscVRegister {
+    /// Abstract register number of the register in the debug module.
+    fn id(self) -> u16 {
+        use RiscVRegister::*;
+        match self {
+            PC => 0x7B1,
+            MEPC => 0x341,
+            MCAUSE => 0x342,
+            MTVAL => 0x343,
+            // The general purpose registers are declared in the order of their numbers.
+            gpr => 0x1000 + gpr as u16,
+        }
+    }
+}
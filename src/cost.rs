@@ -0,0 +1,173 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use crate::memory_map::Address;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Operations whose duration is used to estimate the cost of programming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostOperation {
+    PageErase,
+    PageProgram,
+    ChipErase,
+    /// Transfer of data between host and target.
+    Transfer,
+}
+
+/// Estimates how long flash operations take.
+///
+/// The FlashBuilder uses these estimates to decide between chip erase and page erase.
+/// All weights are in seconds. Implementations may use the measured durations passed to
+/// `record` to refine their estimates during a session.
+pub trait CostModel {
+    /// Time it takes to erase the page at `address` which is `size` bytes long.
+    fn page_erase_weight(&self, address: Address, size: u32) -> f32;
+
+    /// Time it takes to program the page at `address` which is `size` bytes long.
+    /// This does not include the data transfer time.
+    fn page_program_weight(&self, address: Address, size: u32) -> f32;
+
+    /// Time it takes to erase a whole region which is `size` bytes long.
+    fn chip_erase_weight(&self, size: u64) -> f32;
+
+    /// Speed of data transfers between host and target in bytes per second.
+    fn data_transfer_rate(&self) -> f32;
+
+    /// Record how long an operation on `size` bytes took.
+    fn record(&mut self, _operation: CostOperation, _size: u64, _duration: Duration) {}
+}
+
+/// A cost model shared between several users, e.g. a FlashLoader and a Flash.
+impl<M: CostModel> CostModel for Rc<RefCell<M>> {
+    fn page_erase_weight(&self, address: Address, size: u32) -> f32 {
+        self.borrow().page_erase_weight(address, size)
+    }
+
+    fn page_program_weight(&self, address: Address, size: u32) -> f32 {
+        self.borrow().page_program_weight(address, size)
+    }
+
+    fn chip_erase_weight(&self, size: u64) -> f32 {
+        self.borrow().chip_erase_weight(size)
+    }
+
+    fn data_transfer_rate(&self) -> f32 {
+        self.borrow().data_transfer_rate()
+    }
+
+    fn record(&mut self, operation: CostOperation, size: u64, duration: Duration) {
+        self.borrow_mut().record(operation, size, duration)
+    }
+}
+
+/// Accumulated measurements of one kind of operation.
+///
+/// Earlier measurements decay with every new one, so the estimate follows a change of the
+/// probe or the target clock instead of being dominated by old sessions.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Measurement {
+    seconds: f64,
+    bytes: f64,
+}
+
+impl Measurement {
+    /// Weight of the earlier measurements when a new one is added.
+    const DECAY: f64 = 0.8;
+
+    fn add(&mut self, size: u64, duration: Duration) {
+        self.seconds = self.seconds * Self::DECAY + duration.as_secs_f64();
+        self.bytes = self.bytes * Self::DECAY + size as f64;
+    }
+
+    /// Estimated time for `size` bytes, if anything was measured yet.
+    fn estimate(&self, size: u64) -> Option<f32> {
+        if self.bytes > 0.0 {
+            Some((self.seconds / self.bytes * size as f64) as f32)
+        } else {
+            None
+        }
+    }
+}
+
+/// The default cost model.
+///
+/// Starts out with fixed weights and calibrates itself from the measured durations of the
+/// operations performed during the session. It can be persisted in a `CalibrationDatabase`
+/// to keep the calibration across sessions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CalibratedCostModel {
+    page_erase: Measurement,
+    page_program: Measurement,
+    chip_erase: Measurement,
+    transfer: Measurement,
+}
+
+impl CalibratedCostModel {
+    pub const DEFAULT_PAGE_PROGRAM_WEIGHT: f32 = 0.130;
+    pub const DEFAULT_PAGE_ERASE_WEIGHT: f32 = 0.048;
+    pub const DEFAULT_CHIP_ERASE_WEIGHT: f32 = 0.174;
+    pub const DEFAULT_DATA_TRANSFER_B_PER_S: f32 = 40.0 * 1000.0; // ~40KB/s, depends on clock speed, theoretical limit for HID is 56,000 B/s
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CostModel for CalibratedCostModel {
+    fn page_erase_weight(&self, _address: Address, size: u32) -> f32 {
+        self.page_erase.estimate(size as u64).unwrap_or(Self::DEFAULT_PAGE_ERASE_WEIGHT)
+    }
+
+    fn page_program_weight(&self, _address: Address, size: u32) -> f32 {
+        self.page_program.estimate(size as u64).unwrap_or(Self::DEFAULT_PAGE_PROGRAM_WEIGHT)
+    }
+
+    fn chip_erase_weight(&self, size: u64) -> f32 {
+        self.chip_erase.estimate(size).unwrap_or(Self::DEFAULT_CHIP_ERASE_WEIGHT)
+    }
+
+    fn data_transfer_rate(&self) -> f32 {
+        match self.transfer.estimate(1) {
+            Some(seconds_per_byte) if seconds_per_byte > 0.0 => 1.0 / seconds_per_byte,
+            _ => Self::DEFAULT_DATA_TRANSFER_B_PER_S,
+        }
+    }
+
+    fn record(&mut self, operation: CostOperation, size: u64, duration: Duration) {
+        match operation {
+            CostOperation::PageErase => self.page_erase.add(size, duration),
+            CostOperation::PageProgram => self.page_program.add(size, duration),
+            CostOperation::ChipErase => self.chip_erase.add(size, duration),
+            CostOperation::Transfer => self.transfer.add(size, duration),
+        }
+    }
+}
+
+#[test]
+fn calibrated_cost_model_uses_measurements() {
+    let mut model = CalibratedCostModel::new();
+    assert_eq!(model.page_erase_weight(0x0, 1024), CalibratedCostModel::DEFAULT_PAGE_ERASE_WEIGHT);
+    assert_eq!(model.data_transfer_rate(), CalibratedCostModel::DEFAULT_DATA_TRANSFER_B_PER_S);
+
+    model.record(CostOperation::PageErase, 1024, Duration::from_millis(20));
+    model.record(CostOperation::PageErase, 1024, Duration::from_millis(40));
+    model.record(CostOperation::Transfer, 1000, Duration::from_millis(10));
+    assert!((model.page_erase_weight(0x0, 2048) - 0.060).abs() < 0.005);
+    assert!((model.data_transfer_rate() - 100_000.0).abs() < 1.0);
+}
+
+#[test]
+fn calibrated_cost_model_prefers_recent_measurements() {
+    let mut model = CalibratedCostModel::new();
+    for _ in 0..10 {
+        model.record(CostOperation::PageProgram, 1024, Duration::from_millis(100));
+    }
+    for _ in 0..10 {
+        model.record(CostOperation::PageProgram, 1024, Duration::from_millis(10));
+    }
+    // The mean of all measurements would be 55ms.
+    assert!(model.page_program_weight(0x0, 1024) < 0.020);
+}
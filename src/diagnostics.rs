@@ -0,0 +1,156 @@
+use std::fmt;
+
+/// Named cause of a failed flash algorithm call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureCause {
+    WriteProtected,
+    Alignment,
+    Timeout,
+    VerifyMismatch,
+}
+
+impl fmt::Display for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureCause::WriteProtected => write!(f, "the flash is write protected"),
+            FailureCause::Alignment => write!(f, "the address or length is not aligned"),
+            FailureCause::Timeout => write!(f, "the flash controller timed out"),
+            FailureCause::VerifyMismatch => write!(f, "the flash contents do not match the written data"),
+        }
+    }
+}
+
+/// Bits of a flash controller status register which indicate a cause of failure.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StatusBits {
+    pub mask: u32,
+    pub cause: FailureCause,
+}
+
+/// Flash controller status register which is read after a failed flash algorithm call.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StatusRegister {
+    pub name: String,
+    pub address: u32,
+    pub bits: Vec<StatusBits>,
+}
+
+impl StatusRegister {
+    pub fn new(name: &str, address: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            address,
+            bits: vec![],
+        }
+    }
+
+    /// Treat any of the bits in `mask` being set as a failure because of `cause`.
+    pub fn add_bits(&mut self, mask: u32, cause: FailureCause) {
+        self.bits.push(StatusBits { mask, cause });
+    }
+}
+
+/// Maps the return codes of a flash algorithm to named causes of failure.
+///
+/// Algorithms of one family usually share a table. Most algorithms only return a generic error
+/// code, so the table can also list status registers of the flash controller. These are read
+/// after a failure to find the cause if the return code alone does not tell it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ReturnCodeTable {
+    codes: Vec<(u32, FailureCause)>,
+    status_registers: Vec<StatusRegister>,
+}
+
+impl ReturnCodeTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Table of the algorithms for the STM32F4 family.
+    pub fn stm32f4() -> Self {
+        let mut status = StatusRegister::new("FLASH_SR", 0x4002_3C0C);
+        status.add_bits(1 << 4, FailureCause::WriteProtected); // WRPERR
+        status.add_bits(1 << 5 | 1 << 6, FailureCause::Alignment); // PGAERR, PGPERR
+        status.add_bits(1 << 16, FailureCause::Timeout); // BSY
+        let mut table = Self::new();
+        table.add_status_register(status);
+        table
+    }
+
+    /// Decode the return code `code` as a failure because of `cause`.
+    pub fn add_code(&mut self, code: u32, cause: FailureCause) {
+        self.codes.push((code, cause));
+    }
+
+    /// Read `register` after a failure to find its cause.
+    pub fn add_status_register(&mut self, register: StatusRegister) {
+        self.status_registers.push(register);
+    }
+
+    pub fn get_status_registers(&self) -> &[StatusRegister] {
+        &self.status_registers
+    }
+
+    /// Decode the return code `code` of a failed call.
+    ///
+    /// `status` holds the values of the status registers read after the failure, in the order
+    /// of `get_status_registers`. The cause is taken from the return code if it is known and
+    /// from the status registers otherwise.
+    pub fn diagnose(&self, code: u32, status: &[u32]) -> AlgorithmFailure {
+        let cause = self.codes
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, cause)| *cause)
+            .or_else(|| {
+                self.status_registers
+                    .iter()
+                    .zip(status)
+                    .flat_map(|(register, value)| register.bits.iter().filter(move |bits| value & bits.mask != 0))
+                    .map(|bits| bits.cause)
+                    .next()
+            });
+
+        AlgorithmFailure {
+            code,
+            cause,
+            status: self.status_registers
+                .iter()
+                .zip(status)
+                .map(|(register, value)| (register.name.clone(), *value))
+                .collect(),
+        }
+    }
+}
+
+/// Details of a failed flash algorithm call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlgorithmFailure {
+    pub code: u32, // Return code of the algorithm
+    pub cause: Option<FailureCause>,
+    pub status: Vec<(String, u32)>, // Names and values of the status registers read after the failure
+}
+
+impl fmt::Display for AlgorithmFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "return code {}", self.code)?;
+        if let Some(cause) = self.cause {
+            write!(f, ", {}", cause)?;
+        }
+        for (name, value) in &self.status {
+            write!(f, ", {} = {:#010x}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn return_code_table_prefers_known_codes() {
+    let mut table = ReturnCodeTable::stm32f4();
+    assert_eq!(table.diagnose(1, &[1 << 4]).cause, Some(FailureCause::WriteProtected));
+    assert_eq!(table.diagnose(1, &[0]).cause, None);
+
+    table.add_code(2, FailureCause::VerifyMismatch);
+    let failure = table.diagnose(2, &[1 << 5]);
+    assert_eq!(failure.cause, Some(FailureCause::VerifyMismatch));
+    assert_eq!(failure.status, vec![("FLASH_SR".to_owned(), 1 << 5)]);
+}
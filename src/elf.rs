@@ -0,0 +1,139 @@
+use std::convert::TryFrom;
+use std::fmt;
+use crate::memory_map::Address;
+
+/// Segment of an ELF file which is loaded into the memory of the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: Address, // Physical address the segment is loaded to
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    UnsupportedClass(u8), // Contains the EI_CLASS byte of the file.
+    UnsupportedEncoding(u8), // Contains the EI_DATA byte of the file.
+    Truncated,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "the file is not an ELF file"),
+            ElfError::UnsupportedClass(class) => write!(f, "ELF class {} is not supported", class),
+            ElfError::UnsupportedEncoding(encoding) => write!(f, "ELF data encoding {} is not supported", encoding),
+            ElfError::Truncated => write!(f, "the ELF file is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+const PT_LOAD: u64 = 1;
+
+/// Byte order of the fields of an ELF file.
+struct Encoding {
+    big_endian: bool,
+}
+
+impl Encoding {
+    /// Read the `size` byte field at `offset` of `data`.
+    fn field(&self, data: &[u8], offset: usize, size: usize) -> Result<u64, ElfError> {
+        let bytes = data.get(offset..offset + size).ok_or(ElfError::Truncated)?;
+        let value = if self.big_endian {
+            bytes.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+        } else {
+            bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+        };
+        Ok(value)
+    }
+}
+
+/// The `length` bytes at `offset` of `data`.
+fn slice(data: &[u8], offset: u64, length: u64) -> Result<&[u8], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    let length = usize::try_from(length).map_err(|_| ElfError::Truncated)?;
+    let end = start.checked_add(length).ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+/// Get the loadable segments of the ELF file `data`.
+///
+/// Both ELF32 and ELF64 files are supported. The segments are placed at their physical
+/// address, which is where the data is stored in flash. Segments without data in the file,
+/// like `.bss`, are skipped.
+pub fn load_segments(data: &[u8]) -> Result<Vec<Segment>, ElfError> {
+    if data.len() < 16 || &data[0..4] != b"\x7FELF" {
+        return Err(ElfError::NotElf);
+    }
+    let elf64 = match data[4] {
+        1 => false,
+        2 => true,
+        class => return Err(ElfError::UnsupportedClass(class)),
+    };
+    let encoding = match data[5] {
+        1 => Encoding { big_endian: false },
+        2 => Encoding { big_endian: true },
+        encoding => return Err(ElfError::UnsupportedEncoding(encoding)),
+    };
+
+    let (phoff, phentsize, phnum) = if elf64 {
+        (encoding.field(data, 0x20, 8)?, encoding.field(data, 0x36, 2)?, encoding.field(data, 0x38, 2)?)
+    } else {
+        (encoding.field(data, 0x1C, 4)?, encoding.field(data, 0x2A, 2)?, encoding.field(data, 0x2C, 2)?)
+    };
+
+    let mut segments = vec![];
+    for index in 0..phnum {
+        let offset = phoff.checked_add(index * phentsize).ok_or(ElfError::Truncated)?;
+        let header = slice(data, offset, phentsize)?;
+        let (typ, offset, address, size) = if elf64 {
+            (
+                encoding.field(header, 0x00, 4)?,
+                encoding.field(header, 0x08, 8)?,
+                encoding.field(header, 0x18, 8)?,
+                encoding.field(header, 0x20, 8)?,
+            )
+        } else {
+            (
+                encoding.field(header, 0x00, 4)?,
+                encoding.field(header, 0x04, 4)?,
+                encoding.field(header, 0x0C, 4)?,
+                encoding.field(header, 0x10, 4)?,
+            )
+        };
+
+        if typ == PT_LOAD && size > 0 {
+            segments.push(Segment {
+                address,
+                data: slice(data, offset, size)?.to_vec(),
+            });
+        }
+    }
+    Ok(segments)
+}
+
+#[test]
+fn load_segments_reads_elf64_physical_addresses() {
+    let mut file = vec![0; 64 + 56];
+    file[0..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1]);
+    file[0x20..0x28].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+    file[0x36..0x38].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    file[0x38..0x3A].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+    file[64..68].copy_from_slice(&1u32.to_le_bytes()); // p_type
+    file[72..80].copy_from_slice(&120u64.to_le_bytes()); // p_offset
+    file[88..96].copy_from_slice(&0x1_2000_0000u64.to_le_bytes()); // p_paddr
+    file[96..104].copy_from_slice(&4u64.to_le_bytes()); // p_filesz
+    file.extend(&[1, 2, 3, 4]);
+
+    assert_eq!(
+        load_segments(&file),
+        Ok(vec![Segment { address: 0x1_2000_0000, data: vec![1, 2, 3, 4] }])
+    );
+
+    file.truncate(122);
+    assert_eq!(load_segments(&file), Err(ElfError::Truncated));
+    file[4] = 3;
+    assert_eq!(load_segments(&file), Err(ElfError::UnsupportedClass(3)));
+}
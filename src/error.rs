@@ -0,0 +1,111 @@
+use std::fmt;
+use std::path::PathBuf;
+use crate::builder::FlashBuilderError;
+use crate::calibration::CalibrationError;
+use crate::elf::ElfError;
+use crate::flash::FlashError;
+use crate::journal::JournalError;
+use crate::load::FlashLoaderError;
+use crate::page_cache::PageCacheError;
+
+/// Error of any operation of this crate.
+///
+/// The errors of the individual modules convert into it, so they can all be propagated with `?`.
+/// The chain of `source` errors gives the context of a failure: the file, the flash region, the
+/// operation, the address and the return code of the flash algorithm.
+#[derive(Debug)]
+pub enum Error {
+    Flash(FlashError),
+    Builder(FlashBuilderError),
+    Loader(FlashLoaderError),
+    Calibration(CalibrationError),
+    PageCache(PageCacheError),
+    Journal(JournalError),
+    Io(std::io::Error),
+    Hex(ihex::reader::ReaderError),
+    Elf(ElfError),
+    InFile(PathBuf, Box<Error>), // Contains the file the error occurred in.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Flash(e) => e.fmt(f),
+            Error::Builder(e) => e.fmt(f),
+            Error::Loader(e) => e.fmt(f),
+            Error::Calibration(e) => e.fmt(f),
+            Error::PageCache(e) => e.fmt(f),
+            Error::Journal(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::Hex(_) => write!(f, "invalid Intel HEX record"),
+            Error::Elf(e) => e.fmt(f),
+            Error::InFile(path, _) => write!(f, "failed to download {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // The wrapped errors are shown directly, so continue with their sources.
+            Error::Flash(e) => e.source(),
+            Error::Builder(e) => e.source(),
+            Error::Loader(e) => e.source(),
+            Error::Calibration(e) => e.source(),
+            Error::PageCache(e) => e.source(),
+            Error::Journal(e) => e.source(),
+            Error::Io(e) => e.source(),
+            Error::Hex(e) => Some(e),
+            Error::Elf(e) => e.source(),
+            Error::InFile(_, e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<FlashError> for Error {
+    fn from(error: FlashError) -> Self {
+        Error::Flash(error)
+    }
+}
+
+impl From<FlashBuilderError> for Error {
+    fn from(error: FlashBuilderError) -> Self {
+        Error::Builder(error)
+    }
+}
+
+impl From<FlashLoaderError> for Error {
+    fn from(error: FlashLoaderError) -> Self {
+        Error::Loader(error)
+    }
+}
+
+impl From<CalibrationError> for Error {
+    fn from(error: CalibrationError) -> Self {
+        Error::Calibration(error)
+    }
+}
+
+impl From<PageCacheError> for Error {
+    fn from(error: PageCacheError) -> Self {
+        Error::PageCache(error)
+    }
+}
+
+impl From<JournalError> for Error {
+    fn from(error: JournalError) -> Self {
+        Error::Journal(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ElfError> for Error {
+    fn from(error: ElfError) -> Self {
+        Error::Elf(error)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::architecture::{
+    CallingConvention,
+    CortexM,
+    RiscV,
+};
+use crate::cost::CostModel;
+use crate::memory_map::MemoryRegion;
+use crate::preparation::{
+    NoPreparation,
+    Stm32f4Preparation,
+    TargetPreparation,
+};
+use crate::security::{
+    KinetisSecurityBits,
+    NoSecurityBits,
+    SecurityBits,
+};
+
+/// Hooks of a target family, which the FlashLoader installs on the flash of every region.
+///
+/// The defaults fit Cortex-M targets whose flash algorithms need no help from the host.
+pub trait TargetFamily {
+    /// How the flash algorithms of the target are called.
+    fn calling_convention(&self) -> Box<dyn CallingConvention> {
+        Box::new(CortexM)
+    }
+
+    /// Changes to the target which flashing `region` needs.
+    fn preparation(&self, _region: &MemoryRegion) -> Box<dyn TargetPreparation> {
+        Box::new(NoPreparation)
+    }
+
+    /// Configuration values in `region` which can lock the device.
+    fn security_bits(&self, _region: &MemoryRegion) -> Box<dyn SecurityBits> {
+        Box::new(NoSecurityBits)
+    }
+
+    /// Estimates of how long the flash operations in `region` take.
+    ///
+    /// By default the flash estimates them itself. A calibration database set on the
+    /// FlashLoader takes precedence.
+    fn cost_model(&self, _region: &MemoryRegion) -> Option<Box<dyn CostModel>> {
+        None
+    }
+}
+
+/// A Cortex-M target without family specific handling.
+pub struct GenericCortexM;
+
+impl TargetFamily for GenericCortexM {}
+
+/// A RISC-V target without family specific handling.
+pub struct GenericRiscV;
+
+impl TargetFamily for GenericRiscV {
+    fn calling_convention(&self) -> Box<dyn CallingConvention> {
+        Box::new(RiscV)
+    }
+}
+
+/// The Kinetis family, whose flash configuration field can secure the device for good.
+pub struct Kinetis;
+
+impl TargetFamily for Kinetis {
+    fn security_bits(&self, _region: &MemoryRegion) -> Box<dyn SecurityBits> {
+        Box::new(KinetisSecurityBits)
+    }
+}
+
+/// The STM32F4 family.
+#[derive(Default)]
+pub struct Stm32f4 {
+    hse_frequency: Option<u32>,
+}
+
+impl Stm32f4 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the frequency of the external oscillator of the board in Hz.
+    pub fn set_hse_frequency(&mut self, hz: u32) {
+        self.hse_frequency = Some(hz);
+    }
+}
+
+impl TargetFamily for Stm32f4 {
+    fn preparation(&self, _region: &MemoryRegion) -> Box<dyn TargetPreparation> {
+        let mut preparation = Stm32f4Preparation::new();
+        if let Some(hz) = self.hse_frequency {
+            preparation.set_hse_frequency(hz);
+        }
+        Box::new(preparation)
+    }
+}
@@ -1,38 +1,75 @@
-// # Program to compute the CRC of sectors.  This works on cortex-m processors.
-// # Code is relocatable and only needs to be on a 4 byte boundary.
-// # 200 bytes of executable data below + 1024 byte crc table = 1224 bytes
-// # Usage requirements:
-// # -In memory reserve 0x600 for code & table
-// # -Make sure data buffer is big enough to hold 4 bytes for each page that could be checked (ie.  >= num pages * 4)
-// analyzer = (
-//     0x2780b5f0, 0x25004684, 0x4e2b2401, 0x447e4a2b, 0x0023007f, 0x425b402b, 0x40130868, 0x08584043,
-//     0x425b4023, 0x40584013, 0x40200843, 0x40104240, 0x08434058, 0x42404020, 0x40584010, 0x40200843,
-//     0x40104240, 0x08434058, 0x42404020, 0x40584010, 0x40200843, 0x40104240, 0x08584043, 0x425b4023,
-//     0x40434013, 0xc6083501, 0xd1d242bd, 0xd01f2900, 0x46602301, 0x469c25ff, 0x00894e11, 0x447e1841,
-//     0x88034667, 0x409f8844, 0x2f00409c, 0x2201d012, 0x4252193f, 0x34017823, 0x402b4053, 0x599b009b,
-//     0x405a0a12, 0xd1f542bc, 0xc00443d2, 0xd1e74281, 0xbdf02000, 0xe7f82200, 0x000000b2, 0xedb88320,
-//     0x00000042, 
-//     )
-
-
+/// Program to compute the CRC of sectors.  This works on cortex-m processors.
+/// Code is relocatable and only needs to be on a 4 byte boundary.
+/// 200 bytes of executable data below + 1024 byte crc table = 1224 bytes
+/// Usage requirements:
+/// - In memory reserve 0x600 for code & table
+/// - Make sure data buffer is big enough to hold 4 bytes for each page that could be checked (ie.  >= num pages * 4)
+const ANALYZER: [u32; 49] = [
+    0x2780b5f0, 0x25004684, 0x4e2b2401, 0x447e4a2b, 0x0023007f, 0x425b402b, 0x40130868, 0x08584043,
+    0x425b4023, 0x40584013, 0x40200843, 0x40104240, 0x08434058, 0x42404020, 0x40584010, 0x40200843,
+    0x40104240, 0x08434058, 0x42404020, 0x40584010, 0x40200843, 0x40104240, 0x08584043, 0x425b4023,
+    0x40434013, 0xc6083501, 0xd1d242bd, 0xd01f2900, 0x46602301, 0x469c25ff, 0x00894e11, 0x447e1841,
+    0x88034667, 0x409f8844, 0x2f00409c, 0x2201d012, 0x4252193f, 0x34017823, 0x402b4053, 0x599b009b,
+    0x405a0a12, 0xd1f542bc, 0xc00443d2, 0xd1e74281, 0xbdf02000, 0xe7f82200, 0x000000b2, 0xedb88320,
+    0x00000042,
+];
+
+/// Longest pause between two polls of the core while an algorithm function runs.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{
+    Duration,
+    Instant,
+};
+use crate::architecture::{
+    CallingConvention,
+    CortexM,
+    FunctionCall,
+};
+use crate::cost::{
+    CalibratedCostModel,
+    CostModel,
+    CostOperation,
+};
+use crate::diagnostics::AlgorithmFailure;
 use crate::flash_algorithm::{
     FlashAlgorithm,
     FlashAlgorithmInstruction::*,
     FlashAlgorithmLocation::*,
 };
-use crate::target::Target;
-use crate::memory_map::MemoryRegion;
+use crate::preparation::{
+    NoPreparation,
+    TargetPreparation,
+};
+use crate::security::{
+    LockingPolicy,
+    NoSecurityBits,
+    SecurityBits,
+};
+use crate::target::{
+    Target,
+    TargetState,
+};
+use crate::memory_map::{
+    Address,
+    MemoryRegion,
+};
 
 #[derive(Debug)]
 pub struct PageInfo {
-    pub(crate) base_addr: u32, // Page start address
+    pub(crate) base_addr: Address, // Page start address
     pub(crate) size: u32, // Page size
     pub(crate) erase_weight: f32, // Time it takes to erase a page
     pub(crate) program_weight: f32, // Time it takes to program a page (Not including data transfer time)
 }
 
 impl PageInfo {
-    pub fn new(base_addr: u32, size: u32, erase_weight: f32, program_weight: f32) -> Self {
+    pub fn new(base_addr: Address, size: u32, erase_weight: f32, program_weight: f32) -> Self {
         Self {
             base_addr,
             erase_weight,
@@ -42,14 +79,39 @@ impl PageInfo {
     }
 }
 
+/// State of the core when a flash algorithm call timed out.
+#[derive(Debug, Clone)]
+pub struct TimeoutContext {
+    pub function: u32, // Address of the called function
+    pub timeout: Duration,
+    pub pc: u32,
+    pub return_address: u32, // LR on ARM, ra on RISC-V
+    pub sp: u32,
+    pub fault_status: Vec<(&'static str, u32)>, // Names and values of the fault status registers
+}
+
+impl fmt::Display for TimeoutContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "function at {:#010x} did not return within {:?} (PC = {:#010x}, return address = {:#010x}, SP = {:#010x}",
+            self.function, self.timeout, self.pc, self.return_address, self.sp
+        )?;
+        for (name, value) in &self.fault_status {
+            write!(f, ", {} = {:#010x}", name, value)?;
+        }
+        write!(f, ")")
+    }
+}
+
 pub struct FlashInfo {
-    pub(crate) rom_start: u32,
+    pub(crate) rom_start: Address,
     pub(crate) erase_weight: f32,
     pub(crate) crc_supported: bool,
 }
 
 impl FlashInfo {
-    pub fn new(rom_start: u32, erase_weight: f32, crc_supported: bool) -> Self {
+    pub fn new(rom_start: Address, erase_weight: f32, crc_supported: bool) -> Self {
         Self {
             rom_start, // Starting address of ROM
             erase_weight, // Time it takes to perform a chip erase
@@ -64,25 +126,85 @@ impl FlashInfo {
 /// programming only within that region's address range. To program images that cross flash
 /// memory region boundaries, use the FlashLoader or FileProgrammer structs.
 pub struct Flash {
-    target: Target,
+    pub(crate) target: Rc<Target>,
     pub(crate) region: MemoryRegion,
     flash_algorithm: FlashAlgorithm,
     pub is_erase_all_supported: bool,
     pub is_double_buffering_supported: bool,
     did_prepare_target: bool,
     active_operation: FlashOperation,
+    blank_check_after_erase: bool,
+    flash_algo_debug: bool,
+    calling_convention: Box<dyn CallingConvention>,
+    preparation: Box<dyn TargetPreparation>,
+    core_clock: Option<u32>,
+    measured_core_clock: Option<u32>,
+    security_bits: Box<dyn SecurityBits>,
+    locking_policy: LockingPolicy,
+    cost_model: Box<dyn CostModel>,
 }
 
+#[derive(Debug)]
 pub enum FlashError {
-    Init(u32),
-    Uninit(u32),
-    EraseAll(u32),
-    ErasePage(u32, u32), // (err_code, address)
-    ProgramPage(u32, u32), // (err_code, address)
+    Init(AlgorithmFailure),
+    Uninit(AlgorithmFailure),
+    EraseAll(AlgorithmFailure),
+    ErasePage(AlgorithmFailure, Address), // (failure, address)
+    ProgramPage(AlgorithmFailure, Address), // (failure, address)
     WrongOperationOngoing(FlashOperation),
     EraseAllNotSupported,
+    NotBlankAfterErase(Address), // Contains the address of the erased range.
+    RangeNotInRegion(Address, Address), // (start, end)
+    AddressNotSupported(Address), // Contains the address which does not fit into an algorithm argument.
+    AnalyzerNotSupported,
+    InvalidCrcSector(Address, u32), // (address, size)
+    Analyzer(u32), // Contains the return code of the analyzer.
+    AlgorithmCheckFailed(Vec<String>), // Contains the descriptions of the failed checks.
+    Timeout(TimeoutContext),
+    UnexpectedHalt(u32, Vec<(&'static str, u32)>), // (PC, names and values of the fault status registers)
+    TargetPreparation(String), // Contains the description of the failed step.
+    LockingValue(Address), // Contains the address of the value which would lock the device.
+    UnalignedProgram(Address, usize, u32), // (address, length, minimum program length)
+    BreakpointNotReserved(u32), // Contains the first word of the algorithm code.
 }
 
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use FlashError::*;
+        match self {
+            Init(failure) => write!(f, "flash algorithm Init failed with {}", failure),
+            Uninit(failure) => write!(f, "flash algorithm UnInit failed with {}", failure),
+            EraseAll(failure) => write!(f, "flash algorithm EraseChip failed with {}", failure),
+            ErasePage(failure, address) => write!(f, "flash algorithm EraseSector failed at {:#010x} with {}", address, failure),
+            ProgramPage(failure, address) => write!(f, "flash algorithm ProgramPage failed at {:#010x} with {}", address, failure),
+            WrongOperationOngoing(operation) => write!(f, "the flash algorithm is initialized for {:?}", operation),
+            EraseAllNotSupported => write!(f, "the flash algorithm can not erase the whole chip"),
+            NotBlankAfterErase(address) => write!(f, "flash at {:#010x} is not blank after erasing it", address),
+            RangeNotInRegion(start, end) => write!(f, "range {:#010x}..{:#010x} is not inside the flash region", start, end),
+            AddressNotSupported(address) => write!(f, "address {:#010x} does not fit into the 32-bit arguments of the flash algorithm", address),
+            AnalyzerNotSupported => write!(f, "the flash algorithm does not reserve RAM for the CRC analyzer"),
+            InvalidCrcSector(address, size) => write!(f, "the CRC analyzer can not check {} bytes at {:#010x}", size, address),
+            Analyzer(code) => write!(f, "the CRC analyzer failed with return code {}", code),
+            AlgorithmCheckFailed(problems) => write!(f, "flash algorithm misbehaved: {}", problems.join(", ")),
+            Timeout(context) => write!(f, "flash algorithm timed out: {}", context),
+            LockingValue(address) => write!(f, "the value at {:#010x} would lock the device", address),
+            UnalignedProgram(address, length, min_length) => write!(f, "can not program {} bytes at {:#010x} in units of {} bytes", length, address, min_length),
+            TargetPreparation(step) => write!(f, "could not prepare the target for flashing: {}", step),
+            BreakpointNotReserved(word) => write!(f, "the first word {:#010x} of the flash algorithm is not reserved for the return breakpoint", word),
+            UnexpectedHalt(pc, fault_status) => {
+                write!(f, "flash algorithm halted at {:#010x} instead of the return breakpoint", pc)?;
+                for (name, value) in fault_status {
+                    write!(f, ", {} = {:#010x}", name, value)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for FlashError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FlashOperation {
     // Erase all or page erase.
     Erase = 1,
@@ -95,11 +217,11 @@ pub enum FlashOperation {
 }
 
 impl Flash {
-    const DEFAULT_PAGE_PROGRAM_WEIGHT: f32 = 0.130;
-    const DEFAULT_PAGE_ERASE_WEIGHT: f32 = 0.048;
-    const DEFAULT_CHIP_ERASE_WEIGHT: f32 = 0.174;
+    const BLANK_CHECK_CHUNK_SIZE: u64 = 1024;
+    /// Timeout of calls without a timeout in the algorithm, per sector they work on.
+    const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(1);
 
-    pub fn new(target: Target, region: MemoryRegion, flash_algorithm: FlashAlgorithm) -> Self {
+    pub fn new(target: Rc<Target>, region: MemoryRegion, flash_algorithm: FlashAlgorithm) -> Self {
         // self.target = target
         // self.flash_algorithm = flash_algorithm
         // self.flash_algo_debug = False
@@ -143,6 +265,15 @@ impl Flash {
             is_double_buffering_supported: false,
             did_prepare_target: false,
             active_operation: FlashOperation::None,
+            blank_check_after_erase: false,
+            flash_algo_debug: false,
+            calling_convention: Box::new(CortexM),
+            preparation: Box::new(NoPreparation),
+            core_clock: None,
+            measured_core_clock: None,
+            security_bits: Box::new(NoSecurityBits),
+            locking_policy: LockingPolicy::default(),
+            cost_model: Box::new(CalibratedCostModel::new()),
         }
     }
         
@@ -154,28 +285,70 @@ impl Flash {
     /// Get info about the page that contains this address.
     ///
     /// Override this method if variable page sizes are supported.
-    pub fn get_page_info(&self, address: u32) -> Option<PageInfo> {
+    pub fn get_page_info(&self, address: Address) -> Option<PageInfo> {
         if !self.region.contains_address(address) {
             None
         } else {
-            Some(PageInfo::new(address - (address % self.region.blocksize), self.region.blocksize, Self::DEFAULT_PAGE_ERASE_WEIGHT, Self::DEFAULT_PAGE_PROGRAM_WEIGHT))
+            let base_addr = address - (address % self.region.blocksize as u64);
+            let size = self.region.blocksize;
+            Some(PageInfo::new(
+                base_addr,
+                size,
+                self.cost_model.page_erase_weight(base_addr, size),
+                self.cost_model.page_program_weight(base_addr, size)
+            ))
         }
     }
 
+    /// Get the smallest amount of data in bytes which can be programmed at `address`.
+    ///
+    /// Without a minimum program length in the flash algorithm, only whole pages can be programmed.
+    pub fn get_min_program_length(&self, address: Address) -> Option<u32> {
+        self.flash_algorithm.get_min_program_length()
+            .or_else(|| self.get_page_info(address).map(|info| info.size))
+    }
+
     /// Get info about the flash.
     ///
     /// Override this method to return different values.
     pub fn get_flash_info(&self) -> FlashInfo {
-        FlashInfo::new(self.region.start, Self::DEFAULT_CHIP_ERASE_WEIGHT, false) // self.use_analyzer (TODO:)
+        FlashInfo::new(self.region.start, self.cost_model.chip_erase_weight(self.region.length), self.flash_algorithm.is_analyzer_supported())
     }
 
+    /// Speed of data transfers between host and target in bytes per second.
+    pub fn get_data_transfer_rate(&self) -> f32 {
+        self.cost_model.data_transfer_rate()
+    }
+
+    /// Replace the model used to estimate the duration of flash operations.
+    ///
+    /// By default a `CalibratedCostModel` is used.
+    pub fn set_cost_model(&mut self, cost_model: Box<dyn CostModel>) {
+        self.cost_model = cost_model;
+    }
+
+    /// Uninit the flash algorithm and undo the preparation of the target.
+    ///
+    /// The target is restored even if uninit fails. The first error is returned.
     pub fn cleanup(&mut self) -> Result<(), FlashError> {
-        self.uninit()?;
+        let uninit = self.uninit();
+        let restore = if self.did_prepare_target {
+            self.did_prepare_target = false;
+            self.preparation.restore(&self.target)
+        } else {
+            Ok(())
+        };
+        uninit.and(restore)
+    }
+
+    /// Reset the target after a failure, so the flash algorithm is downloaded again on the next `init`.
+    pub fn reset_and_reload(&mut self) {
+        self.target.reset_stop_on_reset();
+        self.active_operation = FlashOperation::None;
         self.did_prepare_target = false;
-        Ok(())
     }
 
-    pub fn uninit(&self) -> Result<(), FlashError> {
+    pub fn uninit(&mut self) -> Result<(), FlashError> {
         match self.active_operation {
             FlashOperation::None => (),
             o => {
@@ -186,11 +359,12 @@ impl Flash {
                     None,
                     None,
                     None,
-                    false
-                );
+                    false,
+                    Self::DEFAULT_CALL_TIMEOUT
+                )?;
                 
                 // check the return code
-                if result != 0 { return Err(FlashError::Uninit(result)); }
+                if result != 0 { return Err(FlashError::Uninit(self.diagnose(result))); }
             }
         }
         self.active_operation = FlashOperation::None;
@@ -198,25 +372,40 @@ impl Flash {
     }
 
     /// Prepare the flash algorithm for performing erase and program operations.
-    pub fn init(&self, operation: FlashOperation) -> Result<(), FlashError> {
-        let address = self.get_flash_info().rom_start;
-        let clock = 0; // TODO: Maybe make this generic?
-        
+    ///
+    /// Fails with `FlashError::AddressNotSupported` if the region starts above 4 GiB, where the
+    /// 32-bit arguments of the flash algorithm cannot reach.
+    pub fn init(&mut self, operation: FlashOperation) -> Result<(), FlashError> {
+        let address = self.algorithm_address(self.get_flash_info().rom_start)?;
+
         self.target.halt();
         if !self.did_prepare_target {
-            self.target.set_target_state("PROGRAM");
-            // TODO: This was pass;
-            // self.prepare_target();
+            if let Err(error) = self.preparation.prepare(&self.target) {
+                // Undo the part of the preparation which succeeded. The first error is reported.
+                let _ = self.preparation.restore(&self.target);
+                return Err(error);
+            }
+            self.measured_core_clock = self.preparation.core_clock(&self.target);
 
             // Load flash algo code into target RAM.
-            self.target.write_memory_block32(
-                self.flash_algorithm.get_address(LoadAddress),
-                self.flash_algorithm.get_instruction_list()
-            );
+            let code = match self.algorithm_code() {
+                Ok(code) => code,
+                Err(error) => {
+                    let _ = self.preparation.restore(&self.target);
+                    return Err(error);
+                },
+            };
+            self.target.write_memory_block32(self.flash_algorithm.get_address(LoadAddress).into(), code.as_slice());
 
             self.did_prepare_target = true;
         }
 
+        // Algorithms which do not need the clock ignore it, so 0 is passed if it is unknown.
+        let clock = self.core_clock
+            .or(self.measured_core_clock)
+            .or_else(|| self.flash_algorithm.get_core_clock())
+            .unwrap_or(0);
+
         // update core register to execute the init subroutine
         let result = self.call_function_and_wait(
             self.flash_algorithm.get_instruction(PCInit),
@@ -224,32 +413,43 @@ impl Flash {
             Some(clock),
             Some(operation as u32),
             None,
-            true
-        );
+            true,
+            Self::DEFAULT_CALL_TIMEOUT
+        )?;
 
         // check the return code
-        if result != 0 { return Err(FlashError::Init(result)); }
+        if result != 0 { return Err(FlashError::Init(self.diagnose(result))); }
         
         self.active_operation = operation;
         Ok(())
     }
 
     /// Erase all the flash.
-    pub fn erase_all(&self) -> Result<(), FlashError> {
+    pub fn erase_all(&mut self) -> Result<(), FlashError> {
         if let FlashOperation::Erase = self.active_operation {
             if self.is_erase_all_supported {
                 // update core register to execute the erase_all subroutine
+                let start = Instant::now();
                 let result = self.call_function_and_wait(
                     self.flash_algorithm.get_instruction(PCEraseAll),
                     None,
                     None,
                     None,
                     None,
-                    true
-                );
+                    true,
+                    self.flash_algorithm.get_erase_timeout() * self.sector_count(self.region.length)
+                )?;
 
                 // check the return code
-                if result != 0 { return Err(FlashError::EraseAll(result)); }
+                if result != 0 { return Err(FlashError::EraseAll(self.diagnose(result))); }
+                self.cost_model.record(CostOperation::ChipErase, self.region.length, start.elapsed());
+
+                if self.blank_check_after_erase {
+                    let range = self.region.start..self.region.end();
+                    if !self.blank_check(range)? {
+                        return Err(FlashError::NotBlankAfterErase(self.region.start));
+                    }
+                }
                 Ok(())
             } else {
                 Err(FlashError::EraseAllNotSupported)
@@ -260,20 +460,33 @@ impl Flash {
     }
 
     /// Erase one page.
-    pub fn erase_page(&self, address: u32) -> Result<(), FlashError> {
+    pub fn erase_page(&mut self, address: Address) -> Result<(), FlashError> {
         if let FlashOperation::Erase = self.active_operation {
             // update core register to execute the erase_page subroutine
+            let start = Instant::now();
             let result = self.call_function_and_wait(
                 self.flash_algorithm.get_instruction(PCEraseSector),
-                Some(address),
+                Some(self.algorithm_address(address)?),
                 None,
                 None,
                 None,
-                true
-            );
+                true,
+                self.flash_algorithm.get_erase_timeout()
+            )?;
 
             // check the return code
-            if result != 0 { return Err(FlashError::ErasePage(result, address)); }
+            if result != 0 { return Err(FlashError::ErasePage(self.diagnose(result), address)); }
+            if let Some(info) = self.get_page_info(address) {
+                self.cost_model.record(CostOperation::PageErase, info.size as u64, start.elapsed());
+            }
+
+            if self.blank_check_after_erase {
+                if let Some(info) = self.get_page_info(address) {
+                    if !self.blank_check(info.base_addr..info.base_addr.saturating_add(info.size as u64))? {
+                        return Err(FlashError::NotBlankAfterErase(address));
+                    }
+                }
+            }
             Ok(())
         } else {
             Err(FlashError::WrongOperationOngoing(self.active_operation))
@@ -281,32 +494,144 @@ impl Flash {
     }
 
     /// Flash one or more pages.
-    pub fn program_page(&self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+    pub fn program_page(&mut self, address: Address, data: &[u8]) -> Result<(), FlashError> {
         if let FlashOperation::Program = self.active_operation {
             // prevent security settings from locking the device
-            self.override_security_bits(address, data);
+            let data = self.override_security_bits(address, data)?;
+            let data = data.as_ref();
 
             // first transfer in RAM
-            self.target.write_memory_block8(self.flash_algorithm.get_address(BeginData), data);
+            let start = Instant::now();
+            self.target.write_memory_block8(self.flash_algorithm.get_address(BeginData).into(), data);
+            self.cost_model.record(CostOperation::Transfer, data.len() as u64, start.elapsed());
 
             // update core register to execute the program_page subroutine
+            let start = Instant::now();
             let result = self.call_function_and_wait(
                 self.flash_algorithm.get_instruction(PCProgramPage),
-                Some(address),
+                Some(self.algorithm_address(address)?),
                 Some(data.len() as u32),
                 Some(self.flash_algorithm.get_address(BeginData)),
                 None,
-                true
-            );
+                true,
+                self.flash_algorithm.get_program_timeout()
+            )?;
 
             // check the return code
-            if result != 0 { return Err(FlashError::ProgramPage(result, address)); }
+            if result != 0 { return Err(FlashError::ProgramPage(self.diagnose(result), address)); }
+            self.cost_model.record(CostOperation::PageProgram, data.len() as u64, start.elapsed());
             Ok(())
         } else {
             Err(FlashError::WrongOperationOngoing(self.active_operation))
         }
     }
 
+    /// Flash a portion of a page.
+    ///
+    /// The address and length of `data` have to be aligned to the minimum program length.
+    pub fn program_phrase(&mut self, address: Address, data: &[u8]) -> Result<(), FlashError> {
+        let min_length = self.get_min_program_length(address)
+            .ok_or(FlashError::RangeNotInRegion(address, address + data.len() as u64))?;
+        if min_length == 0 || !address.is_multiple_of(min_length as u64) || !data.len().is_multiple_of(min_length as usize) {
+            return Err(FlashError::UnalignedProgram(address, data.len(), min_length));
+        }
+        self.program_page(address, data)
+    }
+
+    /// Whether the flash algorithm can check a range for being erased without reading it back.
+    pub fn has_blank_check(&self) -> bool {
+        self.flash_algorithm.has_instruction(PCBlankCheck)
+    }
+
+    /// Check if the flash in `range` is erased.
+    ///
+    /// If the flash algorithm exports `BlankCheck` and is initialized, it is used to check the
+    /// range on the target. Otherwise the range is read back in chunks and compared against the
+    /// erased value of the region.
+    pub fn blank_check(&self, range: Range<Address>) -> Result<bool, FlashError> {
+        if !self.region.contains_range(&range) {
+            return Err(FlashError::RangeNotInRegion(range.start, range.end));
+        }
+
+        let algorithm_initialized = !matches!(self.active_operation, FlashOperation::None);
+
+        if algorithm_initialized && self.flash_algorithm.has_instruction(PCBlankCheck) {
+            let length = u32::try_from(range.end - range.start).map_err(|_| FlashError::AddressNotSupported(range.end))?;
+
+            // update core register to execute the blank_check subroutine
+            let result = self.call_function_and_wait(
+                self.flash_algorithm.get_instruction(PCBlankCheck),
+                Some(self.algorithm_address(range.start)?),
+                Some(length),
+                Some(u32::from(self.region.erased_byte_value)),
+                None,
+                false,
+                Self::DEFAULT_CALL_TIMEOUT * self.sector_count(range.end - range.start)
+            )?;
+
+            // BlankCheck returns 0 if the range is blank and 1 if it is not
+            Ok(result == 0)
+        } else {
+            let mut address = range.start;
+            while address < range.end {
+                let size = u64::min(Self::BLANK_CHECK_CHUNK_SIZE, range.end - address);
+                let data = self.target.read_memory_block8(address, size as u32);
+                if !self.region.is_erased(data.as_slice()) {
+                    return Ok(false);
+                }
+                address += size;
+            }
+            Ok(true)
+        }
+    }
+
+    /// Compute the CRC32 of sectors on the target.
+    ///
+    /// `sectors` is a list of `(address, size)` pairs. Every size must be a power of two and
+    /// every address a multiple of its size.
+    pub fn compute_crcs(&self, sectors: &[(Address, u32)]) -> Result<Vec<u32>, FlashError> {
+        if !self.flash_algorithm.is_analyzer_supported() {
+            return Err(FlashError::AnalyzerNotSupported);
+        }
+
+        // Load analyzer code into target RAM.
+        self.target.write_memory_block32(self.flash_algorithm.get_address(AnalyzerAddress).into(), &ANALYZER);
+
+        // Convert address, size pairs into commands
+        // for the crc computation algorithm to preform
+        let mut data = vec![];
+        for &(address, size) in sectors {
+            // Size must be a power of 2 and address must be a multiple of size
+            if !size.is_power_of_two() || address % size as u64 != 0 {
+                return Err(FlashError::InvalidCrcSector(address, size));
+            }
+            let address = self.algorithm_address(address)?;
+            let size_val = size.trailing_zeros();
+            let address_val = address / size;
+            data.push(size_val | (address_val << 16));
+        }
+
+        let begin_data = self.flash_algorithm.get_address(BeginData);
+        self.target.write_memory_block32(begin_data.into(), &data);
+
+        // update core register to execute the subroutine
+        let result = self.call_function_and_wait(
+            self.flash_algorithm.get_address(AnalyzerAddress),
+            Some(begin_data),
+            Some(data.len() as u32),
+            None,
+            None,
+            false,
+            Self::DEFAULT_CALL_TIMEOUT * sectors.len().max(1) as u32
+        )?;
+
+        // check the return code
+        if result != 0 { return Err(FlashError::Analyzer(result)); }
+
+        // Read back the CRCs for each section
+        Ok(self.target.read_memory_block32(begin_data.into(), data.len() as u32))
+    }
+
     fn call_function(
         &self,
         pc: u32,
@@ -316,135 +641,240 @@ impl Flash {
         r3: Option<u32>,
         init: bool
     ) {
-        let instruction_list = vec![];
+        let call = FunctionCall {
+            pc,
+            arguments: [r0, r1, r2, r3],
+            static_base: if init { Some(self.flash_algorithm.get_address(StaticBase)) } else { None },
+            stack_pointer: if init { Some(self.flash_algorithm.get_address(BeginStack)) } else { None },
+            breakpoint: self.flash_algorithm.get_address(LoadAddress),
+        };
+        self.calling_convention.write_call(&self.target, &call);
 
-        // if self.flash_algo_debug {
-        //     // Save vector catch state for use in wait_for_completion()
-        //     self.saved_vector_catch = self.target.get_vector_catch();
-        //     self.target.set_vector_catch(Target.CATCH_ALL);
-        // }
+        // resume target
+        self.target.resume();
+    }
 
-        instruction_list.push(("pc", pc));
-        if let Some(r0) = r0 {
-            instruction_list.push(("r0", r0));
+    // Wait until the breakpoint is hit.
+    //
+    // If the function at `pc` does not return within `timeout`, the core is halted and its state is captured.
+    // If the core halts anywhere but at the breakpoint, the function did not return.
+    fn wait_for_completion(&self, pc: u32, timeout: Duration) -> Result<u32, FlashError> {
+        let start = Instant::now();
+        // Most functions return quickly, so start polling fast and back off for the slow ones.
+        let mut poll_interval = Duration::from_micros(100);
+        while self.target.get_state() == TargetState::Running {
+            let elapsed = start.elapsed();
+            if elapsed > timeout {
+                self.target.halt();
+                let state = self.calling_convention.read_state(&self.target);
+                return Err(FlashError::Timeout(TimeoutContext {
+                    function: pc,
+                    timeout,
+                    pc: state.pc,
+                    return_address: state.return_address,
+                    sp: state.stack_pointer,
+                    fault_status: self.calling_convention.read_fault_status(&self.target),
+                }));
+            }
+            std::thread::sleep(Duration::min(poll_interval, timeout - elapsed));
+            poll_interval = Duration::min(poll_interval * 2, MAX_POLL_INTERVAL);
         }
-        if let Some(r1) = r1 {
-            instruction_list.push(("r1", r1));
+
+        let state = self.calling_convention.read_state(&self.target);
+        if state.pc != self.flash_algorithm.get_address(LoadAddress) {
+            return Err(FlashError::UnexpectedHalt(state.pc, self.calling_convention.read_fault_status(&self.target)));
         }
-        if let Some(r2) = r0 {
-            instruction_list.push(("r2", r2));
+
+        Ok(self.calling_convention.read_return_value(&self.target))
+    }
+
+    /// The flash algorithm as it is loaded into RAM.
+    fn algorithm_code(&self) -> Result<Vec<u32>, FlashError> {
+        let breakpoint = self.calling_convention.breakpoint_instruction();
+        reserve_breakpoint(self.flash_algorithm.get_instruction_list(), breakpoint)
+    }
+
+    /// Number of sectors in `size` bytes of the region, at least one.
+    fn sector_count(&self, size: u64) -> u32 {
+        u64::max(1, size / self.region.blocksize as u64).min(u32::MAX as u64) as u32
+    }
+
+    /// Convert `address` into an argument of the flash algorithm, which is 32 bits wide.
+    fn algorithm_address(&self, address: Address) -> Result<u32, FlashError> {
+        u32::try_from(address).map_err(|_| FlashError::AddressNotSupported(address))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn call_function_and_wait(
+        &self,
+        pc: u32,
+        r0: Option<u32>,
+        r1: Option<u32>,
+        r2: Option<u32>,
+        r3: Option<u32>,
+        init: bool,
+        timeout: Duration
+    ) -> Result<u32, FlashError> {
+        // Stop on any exception, so a faulting algorithm does not run off into the fault handlers.
+        let saved_vector_catch = if self.flash_algo_debug {
+            let vector_catch = self.target.get_vector_catch();
+            self.target.set_vector_catch(Target::CATCH_ALL);
+            Some(vector_catch)
+        } else {
+            None
+        };
+
+        self.call_function(pc, r0, r1, r2, r3, init);
+        let result = self.wait_for_completion(pc, timeout);
+
+        if let Some(vector_catch) = saved_vector_catch {
+            let analyzer_loaded = pc == self.flash_algorithm.get_address(AnalyzerAddress);
+            let check = match result {
+                Ok(_) => self.check_algorithm_state(analyzer_loaded),
+                Err(_) => Ok(()),
+            };
+            self.target.set_vector_catch(vector_catch);
+            check?;
         }
-        if let Some(r3) = r3 {
-            instruction_list.push(("r3", r3));
+        result
+    }
+
+    /// Check that an algorithm call returned properly and left the algorithm intact.
+    ///
+    /// The analyzer is only checked if it was loaded for the call.
+    fn check_algorithm_state(&self, analyzer_loaded: bool) -> Result<(), FlashError> {
+        let mut problems = vec![];
+
+        let state = self.calling_convention.read_state(&self.target);
+
+        if let Some(exception) = state.exception {
+            if exception != 0 {
+                problems.push(format!("no exception should be active but exception {:#x} is", exception));
+            }
         }
-        if init {
-            instruction_list.push(("r9", self.flash_algorithm.get_address(StaticBase)));
-            instruction_list.push(("sp", self.flash_algorithm.get_address(BeginStack)));
+
+        // Frame pointer should not change
+        let expected_fp = self.flash_algorithm.get_address(StaticBase);
+        let fp = state.static_base;
+        if fp != expected_fp {
+            problems.push(format!("frame pointer should be {:#010x} but is {:#010x}", expected_fp, fp));
         }
 
-        instruction_list.push(("lr", self.flash_algorithm.get_address(LoadAddress) + 1));
-        self.target.write_core_registers_raw(instruction_list);
+        // Stack pointer should return to original value after function call
+        let expected_sp = self.flash_algorithm.get_address(BeginStack);
+        let sp = state.stack_pointer;
+        if sp != expected_sp {
+            problems.push(format!("stack pointer should be {:#010x} but is {:#010x}", expected_sp, sp));
+        }
 
-        // resume target
-        self.target.resume();
+        // Only the code is checked, the data sections of the algorithm are expected to change.
+        if let Some(code_size) = self.flash_algorithm.get_code_size() {
+            let code = self.algorithm_code()?;
+            let words = usize::min((code_size as usize).div_ceil(4), code.len());
+            let algorithm = self.target.read_memory_block32(self.flash_algorithm.get_address(LoadAddress).into(), words as u32);
+            if algorithm.as_slice() != &code[..words] {
+                problems.push("flash algorithm overwritten".to_owned());
+            }
+        }
+
+        if analyzer_loaded {
+            let analyzer = self.target.read_memory_block32(self.flash_algorithm.get_address(AnalyzerAddress).into(), ANALYZER.len() as u32);
+            if analyzer.as_slice() != &ANALYZER[..] {
+                problems.push("analyzer overwritten".to_owned());
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(FlashError::AlgorithmCheckFailed(problems))
+        }
     }
 
-    // Wait until the breakpoint is hit.
-    fn wait_for_completion(&self) -> u32 {
-        while self.target.get_state() == Target.TARGET_RUNNING {};
-
-        // if self.flash_algo_debug {
-        //     regs = self.target.read_core_registers_raw(list(range(19)) + [20])
-        //     println!("ALGO DBG: Registers after flash algo: [%s]", " ".join(regs.map(format!("{:08x}", r))));
-
-        //     let expected_fp = self.flash_algorithm.get_address(StaticBase);
-        //     let expected_sp = self.flash_algorithm.get_address(BeginStack);
-        //     let expected_pc = self.flash_algorithm.get_address(LoadAddress);
-        //     let expected_flash_algo = self.flash_algorithm.get_instructions();
-        //     if self.use_analyzer:
-        //         expected_analyzer = analyzer
-        //     final_ipsr = self.target.read_core_register('ipsr')
-        //     final_fp = self.target.read_core_register('r9')
-        //     final_sp = self.target.read_core_register('sp')
-        //     final_pc = self.target.read_core_register('pc')
-        //     #TODO - uncomment if Read/write and zero init sections can be moved into a separate flash algo section
-        //     #final_flash_algo = self.target.read_memory_block32(&self.flash_algorithm['load_address'], len(&self.flash_algorithm['instructions']))
-        //     #if self.use_analyzer:
-        //     #    final_analyzer = self.target.read_memory_block32(&self.flash_algorithm['analyzer_address'], len(analyzer))
-
-        //     error = False
-        //     if final_ipsr != 0:
-        //         LOG.error("IPSR should be 0 but is 0x%x", final_ipsr)
-        //         error = True
-        //     if final_fp != expected_fp:
-        //         # Frame pointer should not change
-        //         LOG.error("Frame pointer should be 0x%x but is 0x%x" % (expected_fp, final_fp))
-        //         error = True
-        //     if final_sp != expected_sp:
-        //         # Stack pointer should return to original value after function call
-        //         LOG.error("Stack pointer should be 0x%x but is 0x%x" % (expected_sp, final_sp))
-        //         error = True
-        //     if final_pc != expected_pc:
-        //         # PC should be pointing to breakpoint address
-        //         LOG.error("PC should be 0x%x but is 0x%x" % (expected_pc, final_pc))
-        //         error = True
-        //     #TODO - uncomment if Read/write and zero init sections can be moved into a separate flash algo section
-        //     #if not _same(expected_flash_algo, final_flash_algo):
-        //     #    LOG.error("Flash algorithm overwritten!")
-        //     #    error = True
-        //     #if self.use_analyzer and not _same(expected_analyzer, final_analyzer):
-        //     #    LOG.error("Analyzer overwritten!")
-        //     #    error = True
-        //     assert error == False
-        //     self.target.set_vector_catch(&self._saved_vector_catch)
-        // }
-
-        self.target.read_core_register("r0")
-    }
-
-    fn call_function_and_wait(&self, pc: u32, r0: Option<u32>, r1: Option<u32>, r2: Option<u32>, r3: Option<u32>, init: bool) -> u32 {
-        self.call_function(pc, r0, r1, r2, r3, init);
-        self.wait_for_completion()
+    /// Decode the return code of a failed algorithm call.
+    ///
+    /// The status registers listed in the return code table of the algorithm are read to find the cause.
+    fn diagnose(&self, code: u32) -> AlgorithmFailure {
+        let return_codes = self.flash_algorithm.get_return_codes();
+        let status: Vec<u32> = return_codes
+            .get_status_registers()
+            .iter()
+            .map(|register| self.target.read_memory_block32(register.address.into(), 1)[0])
+            .collect();
+        return_codes.diagnose(code, status.as_slice())
     }
 
-    /// TODO: does this function have any use (maybe overridden by another class)
-    fn override_security_bits(&self, address: u32, data: &[u8]) {
-        // Returned data in the PyOCD version ...
+    /// Apply the locking policy to data which is about to be programmed at `address`.
+    pub(crate) fn override_security_bits<'d>(&self, address: Address, data: &'d [u8]) -> Result<Cow<'d, [u8]>, FlashError> {
+        match self.security_bits.find_locking_value(address, data) {
+            None => Ok(Cow::Borrowed(data)),
+            Some(locking_address) => match self.locking_policy {
+                LockingPolicy::Refuse => Err(FlashError::LockingValue(locking_address)),
+                LockingPolicy::Unlock => {
+                    let mut data = data.to_vec();
+                    self.security_bits.unlock(address, data.as_mut_slice());
+                    Ok(Cow::Owned(data))
+                },
+                LockingPolicy::Allow => Ok(Cow::Borrowed(data)),
+            },
+        }
     }
-}
-    
-    // fn restore_target(&self):
-    //     """! @brief Subclasses can override this method to undo any target configuration changes."""
-    //     pass
 
-    // fn compute_crcs(&self, sectors):
-    //     assert self.use_analyzer
-        
-    //     data = []
+    /// Turn on a blank check after every page or chip erase.
+    ///
+    /// When set, erase operations that silently leave data behind fail with
+    /// `FlashError::NotBlankAfterErase`. This slows down erasing.
+    pub fn set_blank_check_after_erase(&mut self, enable: bool) {
+        self.blank_check_after_erase = enable;
+    }
 
-    //     # Load analyzer code into target RAM.
-    //     self.target.write_memory_block32(&self.flash_algorithm['analyzer_address'], analyzer)
+    /// Call the flash algorithm with the calling convention of another architecture.
+    ///
+    /// By default the algorithm is called as Thumb code on a Cortex-M core.
+    pub fn set_calling_convention(&mut self, calling_convention: Box<dyn CallingConvention>) {
+        self.calling_convention = calling_convention;
+    }
 
-    //     # Convert address, size pairs into commands
-    //     # for the crc computation algorithm to preform
-    //     for addr, size in sectors:
-    //         size_val = msb(size)
-    //         addr_val = addr // size
-    //         # Size must be a power of 2
-    //         assert (1 << size_val) == size
-    //         # Address must be a multiple of size
-    //         assert (addr % size) == 0
-    //         val = (size_val << 0) | (addr_val << 16)
-    //         data.append(val)
+    /// Prepare the target for flashing with the hooks of its family.
+    ///
+    /// The target is prepared on the first `init` and restored on `cleanup`. By default the
+    /// target is left as it is.
+    pub fn set_target_preparation(&mut self, preparation: Box<dyn TargetPreparation>) {
+        self.preparation = preparation;
+    }
 
-    //     self.target.write_memory_block32(&self.begin_data, data)
+    /// Check the data to program against the values of the family which lock the device.
+    ///
+    /// By default no values are checked.
+    pub fn set_security_bits(&mut self, security_bits: Box<dyn SecurityBits>) {
+        self.security_bits = security_bits;
+    }
 
-    //     # update core register to execute the subroutine
-    //     result = self._call_function_and_wait(&self.flash_algorithm['analyzer_address'], self.begin_data, len(data))
+    /// Decide what to do with data which would lock the device.
+    ///
+    /// By default such data is refused. Only allow it if locking the device is intended.
+    pub fn set_locking_policy(&mut self, policy: LockingPolicy) {
+        self.locking_policy = policy;
+    }
 
-    //     # Read back the CRCs for each section
-    //     data = self.target.read_memory_block32(&self.begin_data, len(data))
-    //     return data
+    /// Pass `hz` as the core clock to the `Init` function of the flash algorithm.
+    ///
+    /// Algorithms use the clock to set up the flash wait states and timing. Without it, the clock
+    /// is read from the target by its preparation hooks, or taken from the flash algorithm.
+    pub fn set_core_clock(&mut self, hz: u32) {
+        self.core_clock = Some(hz);
+    }
+
+    /// Turn on extra flash algorithm checking.
+    ///
+    /// After every algorithm call the core registers are checked and the algorithm and analyzer
+    /// code is compared against what was loaded. Failed checks are reported as
+    /// `FlashError::AlgorithmCheckFailed`. All exceptions are caught during calls. This slows
+    /// down flash algorithm performance.
+    pub fn set_flash_algo_debug(&mut self, enable: bool) {
+        self.flash_algo_debug = enable;
+    }
+}
 
     // fn start_program_page_with_buffer(&self, bufferNumber, flashPtr):
     //     """!
@@ -474,40 +904,6 @@ impl Flash {
     //     # transfer the buffer to device RAM
     //     self.target.write_memory_block8(&self.page_buffers[bufferNumber], bytes)
 
-    // fn program_phrase(&self, flashPtr, bytes):
-    //     """!
-    //     @brief Flash a portion of a page.
-        
-    //     @exception FlashFailure The address or data length is not aligned to the minimum
-    //         programming length specified in the flash algorithm.
-    //     """
-    //     assert self.active_operation == self.Operation.PROGRAM
-
-    //     # Get min programming length. If one was not specified, use the page size.
-    //     if self.min_program_length:
-    //         min_len = self.min_program_length
-    //     else:
-    //         min_len = self.get_page_info(flashPtr).size
-
-    //     # Require write address and length to be aligned to min write size.
-    //     if flashPtr % min_len:
-    //         raise FlashFailure("unaligned flash write address")
-    //     if len(bytes) % min_len:
-    //         raise FlashFailure("phrase length is unaligned or too small")
-
-    //     # prevent security settings from locking the device
-    //     bytes = self.override_security_bits(flashPtr, bytes)
-
-    //     # first transfer in RAM
-    //     self.target.write_memory_block8(&self.begin_data, bytes)
-
-    //     # update core register to execute the program_page subroutine
-    //     result = self._call_function_and_wait(&self.flash_algorithm['pc_program_page'], flashPtr, len(bytes), self.begin_data)
-
-    //     # check the return code
-    //     if result != 0:
-    //         LOG.error('program_phrase(0x%x) error: %i', flashPtr, result)
-
     // fn flash_block(&self, addr, data, smart_flash=True, chip_erase=None, progress_cb=None, fast_verify=False):
     //     """!
     //     @brief Flash a block of data.
@@ -520,10 +916,51 @@ impl Flash {
     //     info = fb.program(chip_erase, progress_cb, smart_flash, fast_verify)
     //     return info
 
-    // fn set_flash_algo_debug(&self, enable):
-    //     """!
-    //     @brief Turn on extra flash algorithm checking
+/// Put `breakpoint` into the first word of the algorithm `code`, which is reserved for it.
+///
+/// The first word either holds the breakpoint already, like the header of the CMSIS-Pack
+/// algorithms does, or is left empty for it. Anything else is algorithm code, which must not be
+/// overwritten. An empty algorithm consists of the breakpoint only.
+fn reserve_breakpoint(mut code: Vec<u32>, breakpoint: &[u8]) -> Result<Vec<u32>, FlashError> {
+    if code.is_empty() {
+        code.push(0);
+    }
+    let mut first = code[0].to_le_bytes();
+    if code[0] != 0 && &first[..breakpoint.len()] != breakpoint {
+        return Err(FlashError::BreakpointNotReserved(code[0]));
+    }
+    first[..breakpoint.len()].copy_from_slice(breakpoint);
+    code[0] = u32::from_le_bytes(first);
+    Ok(code)
+}
 
-    //     When set this may slow down flash algo performance.
-    //     """
-    //     self.flash_algo_debug = enable
+#[test]
+fn breakpoint_word_is_reserved() {
+    use crate::architecture::{
+        CortexM,
+        RiscV,
+    };
+
+    let bkpt = CortexM.breakpoint_instruction();
+    assert_eq!(reserve_breakpoint(vec![], bkpt).unwrap(), vec![0x0000_BE00]);
+    assert_eq!(reserve_breakpoint(vec![0xE00A_BE00, 0x4770_BA40], bkpt).unwrap(), vec![0xE00A_BE00, 0x4770_BA40]);
+    assert_eq!(reserve_breakpoint(vec![0, 0x4770_BA40], RiscV.breakpoint_instruction()).unwrap(), vec![0x0010_0073, 0x4770_BA40]);
+    match reserve_breakpoint(vec![0x4770_BA40], bkpt) {
+        Err(FlashError::BreakpointNotReserved(0x4770_BA40)) => (),
+        _ => panic!("the algorithm code was overwritten"),
+    }
+}
+
+#[test]
+fn regions_above_4_gib_are_not_supported() {
+    use crate::memory_map::RegionType;
+
+    let region = MemoryRegion::new(RegionType::Flash, 0x1_0000_0000, 0x1000, 0x400, None);
+    let mut flash = Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new());
+    match flash.init(FlashOperation::Erase) {
+        Err(FlashError::AddressNotSupported(0x1_0000_0000)) => (),
+        _ => panic!("the region was initialized"),
+    }
+    // Without the flash algorithm the region is read back, which works at any address.
+    assert!(flash.blank_check(0x1_0000_0000..0x1_0000_0400).is_ok());
+}
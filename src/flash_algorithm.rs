@@ -1,5 +1,33 @@
-#[derive(PartialEq, Eq, Hash)]
-pub struct FlashAlgorithm {}
+use std::time::Duration;
+use crate::diagnostics::ReturnCodeTable;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct FlashAlgorithm {
+    /// Content of erased flash (`FlashDevice.valEmpty`).
+    erased_byte_value: u8,
+    /// RAM address is reserved for the CRC analyzer.
+    analyzer_supported: bool,
+    /// Causes of the failures the algorithm reports.
+    return_codes: ReturnCodeTable,
+    /// Time to program a page in milliseconds (`FlashDevice.toProg`).
+    program_timeout: u32,
+    /// Time to erase a sector in milliseconds (`FlashDevice.toErase`).
+    erase_timeout: u32,
+    /// Core clock in Hz the target runs at when flashing, if the target definition knows it.
+    core_clock: Option<u32>,
+    /// Smallest unit `ProgramPage` can program in bytes, if it can program less than a page.
+    min_program_length: Option<u32>,
+    /// Address of the optional `BlankCheck` function.
+    pc_blank_check: Option<u32>,
+    /// Size of the code section (`PrgCode`) in bytes, which is not written by the algorithm.
+    code_size: Option<u32>,
+}
+
+impl Default for FlashAlgorithm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub enum FlashAlgorithmInstruction {
     PCInit,
@@ -7,6 +35,7 @@ pub enum FlashAlgorithmInstruction {
     PCProgramPage,
     PCEraseSector,
     PCEraseAll,
+    PCBlankCheck,
 }
 
 pub enum FlashAlgorithmLocation {
@@ -15,34 +44,137 @@ pub enum FlashAlgorithmLocation {
     BeginStack,
     BeginData,
     PageSize,
+    AnalyzerAddress,
 }
 
 impl FlashAlgorithm {
     /// TODO: Implement a Macro that actually creates FlashAlgorithm for different targets!
     pub fn new() -> Self {
-        Self {}
+        Self {
+            erased_byte_value: 0xFF,
+            analyzer_supported: false,
+            return_codes: ReturnCodeTable::new(),
+            program_timeout: 100,
+            erase_timeout: 3000,
+            core_clock: None,
+            min_program_length: None,
+            pc_blank_check: None,
+            code_size: None,
+        }
     }
 
     pub fn get_instruction(&self, location: FlashAlgorithmInstruction) -> u32 {
+        use FlashAlgorithmInstruction::*;
         match location {
-            LoadAddress => 0,
             PCInit => 0,
             PCUninit => 0,
             PCProgramPage => 0,
             PCEraseSector => 0,
             PCEraseAll => 0,
+            PCBlankCheck => self.pc_blank_check.unwrap_or(0),
         }
     }
 
+    /// Check if the algorithm exports the given function.
+    ///
+    /// `Init`, `UnInit`, `EraseSector` and `ProgramPage` are mandatory, the others are optional.
+    pub fn has_instruction(&self, instruction: FlashAlgorithmInstruction) -> bool {
+        match instruction {
+            FlashAlgorithmInstruction::PCBlankCheck => self.pc_blank_check.is_some(),
+            _ => true,
+        }
+    }
+
+    /// Set the address of the `BlankCheck` function, if the algorithm exports one.
+    pub fn set_blank_check(&mut self, pc: u32) {
+        self.pc_blank_check = Some(pc);
+    }
+
     pub fn get_address(&self, location: FlashAlgorithmLocation) -> u32 {
+        use FlashAlgorithmLocation::*;
         match location {
+            LoadAddress => 0,
             StaticBase => 0,
             BeginStack => 0,
             BeginData => 0,
             PageSize => 0,
+            AnalyzerAddress => 0,
         }
     }
 
+    pub fn is_analyzer_supported(&self) -> bool {
+        self.analyzer_supported
+    }
+
+    pub fn get_erased_byte_value(&self) -> u8 {
+        self.erased_byte_value
+    }
+
+    /// Set the content of erased flash from `FlashDevice.valEmpty` of the algorithm.
+    ///
+    /// A `MemoryRegion` created with this algorithm takes over the value.
+    pub fn set_erased_byte_value(&mut self, value: u8) {
+        self.erased_byte_value = value;
+    }
+
+    /// Longest time programming a page may take.
+    pub fn get_program_timeout(&self) -> Duration {
+        Duration::from_millis(self.program_timeout as u64)
+    }
+
+    /// Longest time erasing a sector may take.
+    pub fn get_erase_timeout(&self) -> Duration {
+        Duration::from_millis(self.erase_timeout as u64)
+    }
+
+    /// Set the time to program a page in milliseconds from `FlashDevice.toProg` of the algorithm.
+    pub fn set_program_timeout(&mut self, ms: u32) {
+        self.program_timeout = ms;
+    }
+
+    /// Set the time to erase a sector in milliseconds from `FlashDevice.toErase` of the algorithm.
+    pub fn set_erase_timeout(&mut self, ms: u32) {
+        self.erase_timeout = ms;
+    }
+
+    pub fn get_core_clock(&self) -> Option<u32> {
+        self.core_clock
+    }
+
+    /// Pass `hz` as the clock argument to `Init`, unless the clock is set or measured otherwise.
+    pub fn set_core_clock(&mut self, hz: u32) {
+        self.core_clock = Some(hz);
+    }
+
+    pub fn get_min_program_length(&self) -> Option<u32> {
+        self.min_program_length
+    }
+
+    /// Allow programming less than a page in units of `length` bytes, e.g. the ECC word of the flash.
+    pub fn set_min_program_length(&mut self, length: u32) {
+        self.min_program_length = Some(length);
+    }
+
+    pub fn get_return_codes(&self) -> &ReturnCodeTable {
+        &self.return_codes
+    }
+
+    /// Use `return_codes` to decode the failures of the algorithm, e.g. the table of its family.
+    pub fn set_return_codes(&mut self, return_codes: ReturnCodeTable) {
+        self.return_codes = return_codes;
+    }
+
+    pub fn get_code_size(&self) -> Option<u32> {
+        self.code_size
+    }
+
+    /// Set the size of the code section, so the debug checks can tell whether the code was overwritten.
+    ///
+    /// The data sections follow the code and are written by the algorithm, so they are not checked.
+    pub fn set_code_size(&mut self, size: u32) {
+        self.code_size = Some(size);
+    }
+
     pub fn get_instruction_list(&self) -> Vec<u32> {
         vec![]
     }
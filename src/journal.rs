@@ -0,0 +1,222 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    BufRead,
+    BufReader,
+    ErrorKind,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use crate::memory_map::Address;
+use crate::plan::RegionPlan;
+
+/// Flash operation recorded in a ProgrammingJournal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JournalOperation {
+    ChipErase(Address), // Contains the start address of the region.
+    PageErase(Address), // Contains the page address.
+    PageProgram(Address), // Contains the page address.
+}
+
+/// First record of a journal, identifying the device and the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct JournalHeader {
+    version: u32,
+    device_id: String,
+    image_crc: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum JournalEvent {
+    Plan(RegionPlan),
+    Begin(JournalOperation),
+    Complete(JournalOperation),
+}
+
+/// On-disk record of a programming run which allows resuming it after an interruption.
+///
+/// The journal holds one JSON record per line: a header identifying the device and the image,
+/// followed by the plan of every region and the start and completion of every erase and program
+/// operation. Each record is synced to disk before the operation starts, so after an interruption
+/// the journal tells which operations completed and which one was in flight.
+pub struct ProgrammingJournal {
+    path: PathBuf,
+    file: File,
+    plans: Vec<RegionPlan>,
+    completed: BTreeSet<JournalOperation>,
+    in_flight: Option<JournalOperation>,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    InUse, // The journal is still shared when the run is finished.
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalError::Io(_) => write!(f, "could not access the programming journal"),
+            JournalError::Format(_) => write!(f, "the programming journal is malformed"),
+            JournalError::InUse => write!(f, "the programming journal is still in use"),
+        }
+    }
+}
+
+impl std::error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JournalError::Io(e) => Some(e),
+            JournalError::Format(e) => Some(e),
+            JournalError::InUse => None,
+        }
+    }
+}
+
+impl ProgrammingJournal {
+    pub const VERSION: u32 = 1;
+
+    /// Open the journal at `path` for programming the image with checksum `image_crc` on the
+    /// device with unique ID `device_id`.
+    ///
+    /// If the file holds the journal of an interrupted run of the same image on the same device,
+    /// its plans and completed operations are kept so the run can be resumed. Otherwise a new
+    /// journal is started.
+    pub fn open(path: &Path, device_id: &str, image_crc: u32) -> Result<Self, JournalError> {
+        let header = JournalHeader {
+            version: Self::VERSION,
+            device_id: device_id.to_owned(),
+            image_crc,
+        };
+
+        let mut events = vec![];
+        match File::open(path) {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines();
+                let previous = match lines.next() {
+                    Some(line) => serde_json::from_str::<JournalHeader>(&line.map_err(JournalError::Io)?).ok(),
+                    None => None,
+                };
+                if previous.as_ref() == Some(&header) {
+                    for line in lines {
+                        // The last record is incomplete if the run was interrupted while writing it.
+                        match serde_json::from_str(&line.map_err(JournalError::Io)?) {
+                            Ok(event) => events.push(event),
+                            Err(_) => break,
+                        }
+                    }
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(JournalError::Io(e)),
+        };
+
+        // Rewrite the journal, so a torn record at the end does not hide the records appended later.
+        // It is written to a temporary file first, so the old records survive an interruption.
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut journal = Self {
+            path: path.to_owned(),
+            file: File::create(&temp_path).map_err(JournalError::Io)?,
+            plans: vec![],
+            completed: BTreeSet::new(),
+            in_flight: None,
+        };
+        journal.write(&header)?;
+        for event in events {
+            journal.record(event)?;
+        }
+        fs::rename(&temp_path, path).map_err(JournalError::Io)?;
+        journal.file = OpenOptions::new().append(true).open(path).map_err(JournalError::Io)?;
+        Ok(journal)
+    }
+
+    /// Append a record and make sure it reached the disk.
+    fn write<T: Serialize>(&mut self, record: &T) -> Result<(), JournalError> {
+        let mut line = serde_json::to_vec(record).map_err(JournalError::Format)?;
+        line.push(b'\n');
+        self.file.write_all(&line).map_err(JournalError::Io)?;
+        self.file.sync_data().map_err(JournalError::Io)
+    }
+
+    fn record(&mut self, event: JournalEvent) -> Result<(), JournalError> {
+        self.write(&event)?;
+        match event {
+            JournalEvent::Plan(plan) => self.plans.push(plan),
+            JournalEvent::Begin(operation) => self.in_flight = Some(operation),
+            JournalEvent::Complete(operation) => {
+                self.completed.insert(operation);
+                self.in_flight = None;
+            },
+        }
+        Ok(())
+    }
+
+    /// The plan an earlier run recorded for the region starting at `region_start`.
+    pub fn region_plan(&self, region_start: Address) -> Option<&RegionPlan> {
+        self.plans.iter().find(|plan| plan.region_start == region_start)
+    }
+
+    /// Record the plan for a region before any of its operations start.
+    pub fn record_plan(&mut self, plan: RegionPlan) -> Result<(), JournalError> {
+        self.record(JournalEvent::Plan(plan))
+    }
+
+    /// Check if `operation` completed in this or an earlier run.
+    pub fn is_completed(&self, operation: JournalOperation) -> bool {
+        self.completed.contains(&operation)
+    }
+
+    /// The operation which was started but did not complete.
+    pub fn in_flight(&self) -> Option<JournalOperation> {
+        self.in_flight
+    }
+
+    /// Record that `operation` is about to start.
+    pub fn begin(&mut self, operation: JournalOperation) -> Result<(), JournalError> {
+        self.record(JournalEvent::Begin(operation))
+    }
+
+    /// Record that `operation` completed.
+    pub fn complete(&mut self, operation: JournalOperation) -> Result<(), JournalError> {
+        self.record(JournalEvent::Complete(operation))
+    }
+
+    /// Remove the journal after the run completed, so the next run starts from scratch.
+    pub fn finish(self) -> Result<(), JournalError> {
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(JournalError::Io)
+    }
+}
+
+#[test]
+fn journal_is_rewritten_in_place() {
+    let path = std::env::temp_dir().join(format!("flash-rs-journal-{}.json", std::process::id()));
+    let mut journal = ProgrammingJournal::open(&path, "device", 0x1234).unwrap();
+    journal.complete(JournalOperation::PageErase(0x400)).unwrap();
+    journal.begin(JournalOperation::PageProgram(0x400)).unwrap();
+    drop(journal);
+
+    let journal = ProgrammingJournal::open(&path, "device", 0x1234).unwrap();
+    assert!(journal.is_completed(JournalOperation::PageErase(0x400)));
+    assert_eq!(journal.in_flight(), Some(JournalOperation::PageProgram(0x400)));
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    assert!(!PathBuf::from(temp_path).exists());
+    journal.finish().unwrap();
+    assert!(!path.exists());
+}
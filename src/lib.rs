@@ -1,7 +1,23 @@
-mod flash_algorithm;
-mod memory_map;
-mod builder;
-mod load;
-mod common;
-mod flash;
-mod target;
\ No newline at end of file
+pub mod architecture;
+pub mod flash_algorithm;
+pub mod memory_map;
+pub mod builder;
+pub mod calibration;
+pub mod cancel;
+pub mod load;
+pub mod common;
+pub mod core_register;
+pub mod cost;
+pub mod diagnostics;
+pub mod elf;
+pub mod error;
+pub mod family;
+pub mod flash;
+pub mod journal;
+pub mod page_cache;
+pub mod plan;
+pub mod preparation;
+pub mod progress;
+pub mod retry;
+pub mod security;
+pub mod target;
\ No newline at end of file
@@ -1,21 +1,62 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 use crate::memory_map::{
+    Address,
     MemoryRegion,
     RegionType,
 };
+use crate::cancel::CancellationToken;
+use crate::calibration::{
+    CalibrationDatabase,
+    CalibrationError,
+};
+use crate::common::checked_range;
+use crate::cost::CalibratedCostModel;
+use crate::elf;
+use crate::error::Error;
 use crate::flash::Flash;
-use crate::builder::FlashBuilder;
+use crate::builder::{
+    FlashBuilder,
+    FlashBuilderError,
+    ProgrammingInfo,
+};
+use crate::family::{
+    GenericCortexM,
+    TargetFamily,
+};
+use crate::journal::{
+    JournalError,
+    ProgrammingJournal,
+};
 use crate::memory_map::MemoryMap;
-use crate::flash_algorithm::FlashAlgorithm;
-use std::path::Path;
+use crate::page_cache::{
+    PageCacheError,
+    PageHashCache,
+};
+use crate::plan::FlashPlan;
+use crate::retry::RetryPolicy;
+use crate::security::LockingPolicy;
+use crate::progress::{
+    ProgressObserver,
+    ScaledProgress,
+};
+use crate::target::Target;
+use std::fmt;
+use std::ops::Range;
+use std::path::{
+    Path,
+    PathBuf,
+};
 use std::io::{ Read, Seek, SeekFrom };
 use std::fs::File;
 use ihex;
+use ihex::reader::ReaderError;
+use ihex::record::Record;
 
 pub struct Ranges<I: Iterator<Item=usize> + Sized> {
     list: I,
-    start_item: Option<usize>,
-    last_item: Option<usize>
+    start_item: Option<usize>, // First item of the next range, if it was read already.
 }
 
 impl<I: Iterator<Item=usize> + Sized> Ranges<I> {
@@ -23,7 +64,6 @@ impl<I: Iterator<Item=usize> + Sized> Ranges<I> {
         Self {
             list,
             start_item: None,
-            last_item: Some(usize::max_value() - 1)
         }
     }
 }
@@ -32,38 +72,26 @@ impl<I: Iterator<Item=usize> + Sized> Iterator for Ranges<I> {
     type Item = (usize, usize);
 
     fn next(&mut self) -> Option<(usize, usize)> {
-        let r;
-        if self.start_item.is_none() {
-            self.start_item = self.list.next();
-            self.last_item = self.start_item;
-        }
-        loop {
-            if let Some(item) = self.list.next() {
-                if item == self.last_item.unwrap() + 1 {
-                    self.last_item = Some(item);
-                } else {
-                    r = (self.start_item.unwrap(), self.last_item.unwrap());
-                    self.last_item = Some(item);
-                    self.start_item = self.last_item;
-                    break;
-                }
+        let start_item = match self.start_item.take() {
+            Some(item) => item,
+            None => self.list.next()?,
+        };
+        let mut last_item = start_item;
+        for item in &mut self.list {
+            if Some(item) == last_item.checked_add(1) {
+                last_item = item;
             } else {
-                if let Some(last_item) = self.last_item {
-                    self.last_item = None;
-                    return Some((self.start_item.unwrap(), last_item));
-                } else {
-                    return None;
-                }
+                self.start_item = Some(item);
+                break;
             }
         }
-
-        Some(r)
+        Some((start_item, last_item))
     }
 }
 
 /// Accepts a sorted list of byte addresses. Breaks the addresses into contiguous ranges.
 /// Yields 2-tuples of the start and end address for each contiguous range.
-
+///
 /// For instance, the input [0, 1, 2, 3, 32, 33, 34, 35] will yield the following 2-tuples:
 /// (0, 3) and (32, 35).
 pub fn ranges<I: Iterator<Item = usize>>(list: I)-> Ranges<I> {
@@ -73,7 +101,7 @@ pub fn ranges<I: Iterator<Item = usize>>(list: I)-> Ranges<I> {
 pub struct BinOptions {
     /// Memory address at which to program the binary data. If not set, the base
     /// of the boot memory will be used.
-    base_address: Option<u32>,
+    base_address: Option<Address>,
     /// Number of bytes to skip at the start of the binary file. Does not affect the
     /// base address.
     skip: u32,
@@ -92,6 +120,7 @@ pub enum Format {
 /// - Binary (.bin)
 /// - Intel Hex (.hex)
 /// - ELF (.elf or .axf)
+#[derive(Default)]
 pub struct FileDownloader;
 
 impl FileDownloader {
@@ -99,89 +128,96 @@ impl FileDownloader {
         Self {}
     }
 
-    /// Downloads a file at `path` into flash.
-    pub fn download_file(self, path: &Path, format: Format, memory_map: MemoryMap) -> Result<(), ()> {
-        let file = File::open(path).unwrap();
-
-        // IMPORTANT: Change this to an actual memory map of a real
-        let mut loader = FlashLoader::new(memory_map);
+    /// Downloads a file at `path` into flash with `loader`.
+    ///
+    /// The loader knows the target and its memory map.
+    /// The combined progress of all flash regions is reported to `progress`.
+    pub fn download_file(self, path: &Path, format: Format, loader: &mut FlashLoader, progress: &mut dyn ProgressObserver) -> Result<(), Error> {
+        let in_file = |e| Error::InFile(path.to_owned(), Box::new(e));
+        let mut file = File::open(path).map_err(|e| in_file(Error::Io(e)))?;
 
         match format {
-            Format::Bin(options) => self.download_bin(&mut file, &mut loader, options),
-            Format::Elf => self.download_elf(&mut file, &mut loader),
-            Format::Hex => self.download_hex(&mut file, &mut loader),
-        };
+            Format::Bin(options) => self.download_bin(&mut file, loader, options),
+            Format::Elf => self.download_elf(&mut file, loader),
+            Format::Hex => self.download_hex(&mut file, loader),
+        }.map_err(in_file)?;
 
-        loader.commit();
+        loader.commit(progress)?;
 
         Ok(())
     }
 
     /// Starts the download of a binary file.
-    fn download_bin<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader, options: BinOptions) -> Result<(), ()> {
+    fn download_bin<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader, options: BinOptions) -> Result<(), Error> {
         // Skip the specified bytes.
-        file.seek(SeekFrom::Start(options.skip as u64));
+        file.seek(SeekFrom::Start(options.skip as u64))?;
         
         let mut data = vec![];
-        file.read_to_end(&mut data);
+        file.read_to_end(&mut data)?;
 
         loader.add_data(
-            if let Some(address) = options.base_address {
-                address
-            } else {
-                // If no base address is specified use the start of the boot memory.
-                // TODO: Implement this as soon as we know targets.
-                // self._session.target.memory_map.get_boot_memory().start
-                0
-            },
+            // If no base address is specified use the start of the boot memory.
+            // TODO: Implement this as soon as we know targets.
+            // self._session.target.memory_map.get_boot_memory().start
+            options.base_address.unwrap_or_default(),
             data.as_slice()
-        );
+        )?;
 
         Ok(())
     }
 
     /// Starts the download of a hex file.
-    fn download_hex<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader) -> Result<(), ()> {
-        let mut data: String;
-        file.read_to_string(&mut data);
+    fn download_hex<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader) -> Result<(), Error> {
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
 
-        for item in ihex::reader::Reader::new(&data) {
-            if let Ok(record) = item {
-                println!("{:?}", record);
-            } else {
-                return Err(());
-            }
+        for (address, data) in hex_chunks(ihex::reader::Reader::new(&data)).map_err(Error::Hex)? {
+            loader.add_data(address, data.as_slice())?;
         }
         Ok(())
-
-        // hexfile = IntelHex(file_obj)
-        // addresses = hexfile.addresses()
-        // addresses.sort()
-
-        // data_list = list(ranges(addresses))
-        // for start, end in data_list:
-        //     size = end - start + 1
-        //     data = list(hexfile.tobinarray(start=start, size=size))
-        //     self._loader.add_data(start, data)
     }
         
     /// Starts the download of a elf file.
-    fn download_elf<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader) -> Result<(), ()> {
-    // TODO:
-    //     elf = ELFBinaryFile(file_obj, self._session.target.memory_map)
-    //     for section in elf.sections:
-    //         if ((section.type == 'SHT_PROGBITS')
-    //                 and ((section.flags & (SH_FLAGS.SHF_ALLOC | SH_FLAGS.SHF_WRITE)) == SH_FLAGS.SHF_ALLOC)
-    //                 and (section.length > 0)
-    //                 and (section.region.is_flash)):
-    //             LOG.debug("Writing section %s", repr(section))
-    //             self._loader.add_data(section.start, section.data)
-    //         else:
-    //             LOG.debug("Skipping section %s", repr(section))
+    ///
+    /// Segments which are not loaded into flash, like initialized RAM, are skipped.
+    fn download_elf<T: Read + Seek>(self, file: &mut T, loader: &mut FlashLoader) -> Result<(), Error> {
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        for segment in elf::load_segments(data.as_slice())? {
+            let region = loader.memory_map.get_region_for_address(segment.address);
+            if let Some(RegionType::Flash) = region.map(|region| region.typ) {
+                loader.add_data(segment.address, segment.data.as_slice())?;
+            }
+        }
         Ok(())
     }
 }
 
+/// Combine the data records of an Intel HEX file into chunks of contiguous data.
+///
+/// Extended segment and extended linear address records set the base address of the data records which follow them.
+fn hex_chunks<I: Iterator<Item = Result<Record, ReaderError>>>(records: I) -> Result<Vec<(Address, Vec<u8>)>, ReaderError> {
+    let mut chunks: Vec<(Address, Vec<u8>)> = vec![];
+    let mut base: Address = 0;
+    for record in records {
+        match record? {
+            Record::Data { offset, value } => {
+                let address = base + offset as u64;
+                match chunks.last_mut() {
+                    Some((start, data)) if *start + data.len() as u64 == address => data.extend(value),
+                    _ => chunks.push((address, value)),
+                }
+            },
+            Record::ExtendedSegmentAddress(segment) => base = (segment as u64) << 4,
+            Record::ExtendedLinearAddress(upper) => base = (upper as u64) << 16,
+            Record::EndOfFile => break,
+            Record::StartSegmentAddress { .. } | Record::StartLinearAddress(_) => (),
+        }
+    }
+    Ok(chunks)
+}
+
 // class FlashEraser(object):
 //     """! @brief Class that manages high level flash erasing.
     
@@ -350,25 +386,252 @@ impl FileDownloader {
 /// is suppresed and a combined report is logged.
 /// 
 /// Internally, FlashBuilder is used to optimize programming within each memory region.
-pub struct FlashLoader<'a> {
+///
+/// If a calibration database is set, the cost models of the regions are loaded from it and the
+/// calibration measured during programming is stored back on commit.
+pub struct FlashLoader {
     memory_map: MemoryMap,
-    builders: HashMap<MemoryRegion, FlashBuilder<'a>>,
+    target: Rc<Target>,
+    builders: HashMap<MemoryRegion, FlashBuilder>,
     total_data_size: usize,
-    chip_erase: bool,
+    chip_erase: Option<bool>,
+    override_protection: bool,
+    calibration: Option<CalibrationSource>,
+    cost_models: HashMap<Address, Rc<RefCell<CalibratedCostModel>>>,
+    page_cache: Option<(PathBuf, Rc<RefCell<PageHashCache>>)>,
+    cancellation_token: Option<CancellationToken>,
+    journal: Option<(PathBuf, String)>, // Path and unique ID of the device.
+    image_crc: Option<u32>, // Checksum of all data added since the last completed commit.
+    retry_policy: RetryPolicy,
+    core_clock: Option<u32>,
+    locking_policy: LockingPolicy,
+    family: Box<dyn TargetFamily>,
+    flash_algo_debug: bool,
+    blank_check_after_erase: bool,
+    keep_unwritten: bool,
+}
+
+/// Where the calibrated cost models of a FlashLoader are persisted.
+struct CalibrationSource {
+    path: PathBuf,
+    target: String,
+    probe: String,
+    database: CalibrationDatabase,
 }
 
+#[derive(Debug)]
 pub enum FlashLoaderError {
-    MemoryRegionNotDefined(u32), // Contains the faulty address.
-    MemoryRegionNotFlash(u32) // Contains the faulty address.
+    MemoryRegionNotDefined(Address), // Contains the faulty address.
+    MemoryRegionNotFlash(Address), // Contains the faulty address.
+    AddressOverflow(Address), // Contains the address of data which runs past the end of the address space.
+    Builder(FlashBuilderError),
+    Calibration(CalibrationError),
+    PageCache(PageCacheError),
+    Cancelled(Box<ProgrammingInfo>), // Contains the combined report of what was done before cancelling.
+    Journal(JournalError),
+}
+
+impl fmt::Display for FlashLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use FlashLoaderError::*;
+        match self {
+            MemoryRegionNotDefined(address) => write!(f, "no memory region is defined at {:#010x}", address),
+            MemoryRegionNotFlash(address) => write!(f, "the memory region at {:#010x} is not flash", address),
+            AddressOverflow(address) => write!(f, "data at {:#010x} runs past the end of the address space", address),
+            Builder(_) => write!(f, "programming a flash region failed"),
+            Calibration(_) => write!(f, "could not persist the cost model calibration"),
+            PageCache(_) => write!(f, "could not persist the page hash cache"),
+            Cancelled(info) => write!(f, "programming was cancelled after programming {} pages", info.programmed_pages.len()),
+            Journal(_) => write!(f, "could not use the programming journal"),
+        }
+    }
 }
 
-impl<'a> FlashLoader<'a> {
-    pub fn new(memory_map: MemoryMap) -> Self {
+impl std::error::Error for FlashLoaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use FlashLoaderError::*;
+        match self {
+            Builder(e) => Some(e),
+            Calibration(e) => Some(e),
+            PageCache(e) => Some(e),
+            Journal(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl FlashLoader {
+    pub fn new(memory_map: MemoryMap, target: Rc<Target>) -> Self {
         Self {
-            memory_map: memory_map,
+            memory_map,
+            target,
             builders: HashMap::new(),
             total_data_size: 0,
-            chip_erase: false,
+            chip_erase: None,
+            override_protection: false,
+            calibration: None,
+            cost_models: HashMap::new(),
+            page_cache: None,
+            cancellation_token: None,
+            journal: None,
+            image_crc: None,
+            retry_policy: RetryPolicy::new(),
+            core_clock: None,
+            locking_policy: LockingPolicy::default(),
+            family: Box::new(GenericCortexM),
+            flash_algo_debug: false,
+            blank_check_after_erase: false,
+            keep_unwritten: true,
+        }
+    }
+
+    /// Force chip erase with `Some(true)` or page erase with `Some(false)`.
+    ///
+    /// By default the faster method is chosen. Only the first region may be chip erased in a
+    /// commit, the others always use page erase.
+    pub fn set_chip_erase(&mut self, chip_erase: Option<bool>) {
+        self.chip_erase = chip_erase;
+    }
+
+    /// Decide what to do with data which would lock the device, in all regions.
+    ///
+    /// By default such data is refused. Must be called before any data is added.
+    pub fn set_locking_policy(&mut self, policy: LockingPolicy) {
+        self.locking_policy = policy;
+    }
+
+    /// Use the hooks of `family` to flash the regions of the target.
+    ///
+    /// By default the target is a `GenericCortexM`. Must be called before any data is added.
+    pub fn set_target_family(&mut self, family: Box<dyn TargetFamily>) {
+        self.family = family;
+    }
+
+    /// Pass `hz` as the core clock to the flash algorithms of all regions.
+    ///
+    /// Must be called before any data is added.
+    pub fn set_core_clock(&mut self, hz: u32) {
+        self.core_clock = Some(hz);
+    }
+
+    /// Turn on extra flash algorithm checking in all regions, see `Flash::set_flash_algo_debug`.
+    ///
+    /// Must be called before any data is added.
+    pub fn set_flash_algo_debug(&mut self, enable: bool) {
+        self.flash_algo_debug = enable;
+    }
+
+    /// Turn on a blank check after every erase in all regions, see `Flash::set_blank_check_after_erase`.
+    ///
+    /// Must be called before any data is added.
+    pub fn set_blank_check_after_erase(&mut self, enable: bool) {
+        self.blank_check_after_erase = enable;
+    }
+
+    /// Decide how gaps in the data of a page are filled in all regions, see `FlashBuilder::set_keep_unwritten`.
+    ///
+    /// By default gaps keep the current content of the flash. Must be called before any data is added.
+    pub fn set_keep_unwritten(&mut self, keep: bool) {
+        self.keep_unwritten = keep;
+    }
+
+    /// Retry failed page operations according to `policy`.
+    ///
+    /// The error budget of the policy is shared by all regions of a commit.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Keep a journal of every `commit` at `path`, so an interrupted commit can be resumed.
+    ///
+    /// If the previous commit of the same data to the device with unique ID `device_id` was
+    /// interrupted, the next commit skips the operations which completed, verifies the page which
+    /// was in flight and continues from there. The journal is removed once a commit completes.
+    pub fn set_journal(&mut self, path: &Path, device_id: &str) {
+        self.journal = Some((path.to_owned(), device_id.to_owned()));
+    }
+
+    /// Allow cancelling `commit` with `token`.
+    ///
+    /// When cancelled, programming stops after the current page operation and `commit` returns
+    /// `FlashLoaderError::Cancelled` with the pages erased and programmed so far in all regions.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = Some(token);
+    }
+
+    /// Skip pages which are unchanged since the last run according to a host side cache at `path`.
+    ///
+    /// The cache belongs to the device with the unique ID `device_id`. Unchanged pages are
+    /// skipped without any reads from the target. Must be called before any data is added.
+    pub fn set_page_cache(&mut self, path: &Path, device_id: &str) -> Result<(), FlashLoaderError> {
+        let page_cache = PageHashCache::load(path, device_id).map_err(FlashLoaderError::PageCache)?;
+        self.page_cache = Some((path.to_owned(), Rc::new(RefCell::new(page_cache))));
+        Ok(())
+    }
+
+    /// Get the page cache, e.g. to change its validation interval or request a validation.
+    pub fn get_page_cache(&self) -> Option<Rc<RefCell<PageHashCache>>> {
+        self.page_cache.as_ref().map(|(_, page_cache)| page_cache.clone())
+    }
+
+    fn save_page_cache(&self) -> Result<(), FlashLoaderError> {
+        if let Some((path, page_cache)) = &self.page_cache {
+            page_cache.borrow().save(path).map_err(FlashLoaderError::PageCache)?;
+        }
+        Ok(())
+    }
+
+    /// Persist the cost models used to decide between chip and page erase in the database at `path`.
+    ///
+    /// The calibrations are stored per `target` and `probe` combination, as both influence the
+    /// timings. Must be called before any data is added.
+    pub fn set_calibration_database(&mut self, path: &Path, target: &str, probe: &str) -> Result<(), FlashLoaderError> {
+        let database = CalibrationDatabase::load(path).map_err(FlashLoaderError::Calibration)?;
+        self.calibration = Some(CalibrationSource {
+            path: path.to_owned(),
+            target: target.to_owned(),
+            probe: probe.to_owned(),
+            database,
+        });
+        self.cost_models = HashMap::new();
+        Ok(())
+    }
+
+    /// Get the calibrated cost model for the region starting at `region_start`, if calibration is enabled.
+    fn calibrated_cost_model(&mut self, region_start: Address) -> Option<Rc<RefCell<CalibratedCostModel>>> {
+        let calibration = self.calibration.as_ref()?;
+        let model = self.cost_models.entry(region_start).or_insert_with(|| {
+            let model = calibration.database
+                .get(&calibration.target, &calibration.probe, region_start)
+                .cloned()
+                .unwrap_or_default();
+            Rc::new(RefCell::new(model))
+        });
+        Some(model.clone())
+    }
+
+    /// Store the cost models calibrated in this session in the calibration database.
+    fn save_calibration(&mut self) -> Result<(), FlashLoaderError> {
+        if let Some(calibration) = &mut self.calibration {
+            for (region_start, model) in &self.cost_models {
+                calibration.database.update(&calibration.target, &calibration.probe, *region_start, model.borrow().clone());
+            }
+            calibration.database.save(&calibration.path).map_err(FlashLoaderError::Calibration)?;
+        }
+        Ok(())
+    }
+
+    /// Allow erasing and programming the protected ranges of the memory map.
+    pub fn set_override_protection(&mut self, enable: bool) {
+        self.override_protection = enable;
+    }
+
+    /// The address ranges the builders have to leave alone.
+    fn protected_ranges(&self) -> Vec<Range<Address>> {
+        if self.override_protection {
+            vec![]
+        } else {
+            self.memory_map.get_protected_ranges().to_vec()
         }
     }
     
@@ -376,14 +639,44 @@ impl<'a> FlashLoader<'a> {
     fn reset_state(&mut self) {
         self.builders = HashMap::new();
         self.total_data_size = 0;
+        self.image_crc = None;
     }
     
+    /// Create the builder of a flash `region` with the settings of the loader.
+    fn create_builder(&mut self, region: &MemoryRegion) -> FlashBuilder {
+        let algorithm = region.algorithm.clone().unwrap_or_default();
+        let mut flash = Flash::new(self.target.clone(), region.clone(), algorithm);
+        if let Some(cost_model) = self.calibrated_cost_model(region.start) {
+            flash.set_cost_model(Box::new(cost_model));
+        } else if let Some(cost_model) = self.family.cost_model(region) {
+            flash.set_cost_model(cost_model);
+        }
+        if let Some(hz) = self.core_clock {
+            flash.set_core_clock(hz);
+        }
+        flash.set_locking_policy(self.locking_policy);
+        flash.set_calling_convention(self.family.calling_convention());
+        flash.set_target_preparation(self.family.preparation(region));
+        flash.set_security_bits(self.family.security_bits(region));
+        flash.set_flash_algo_debug(self.flash_algo_debug);
+        flash.set_blank_check_after_erase(self.blank_check_after_erase);
+        let mut builder = FlashBuilder::new(flash);
+        builder.set_keep_unwritten(self.keep_unwritten);
+        if let Some(page_cache) = self.get_page_cache() {
+            builder.set_page_cache(page_cache);
+        }
+        builder
+    }
+
     /// Add a chunk of data to be programmed.
     ///
     /// The data may cross flash memory region boundaries, as long as the regions are contiguous.
     /// `address` is the address where the first byte of `data` is located.
     /// `data` is an iterator of u8 bytes to be written at given `address` and onwards.
-    pub fn add_data(&mut self, mut address: u32, data: &[u8]) -> Result<(), FlashLoaderError> {
+    pub fn add_data(&mut self, mut address: Address, data: &[u8]) -> Result<(), FlashLoaderError> {
+        checked_range(address, data.len() as u64).ok_or(FlashLoaderError::AddressOverflow(address))?;
+        // The image changed, so it is identified again by the next commit.
+        self.image_crc = None;
         let size = data.len();
         let mut remaining = size;
         while remaining > 0 {
@@ -392,22 +685,22 @@ impl<'a> FlashLoader<'a> {
             if let Some(region) = possible_region {
                 if let RegionType::Flash = region.typ {
                     // Get our builder instance.
-                    let builder = if self.builders.contains_key(&region) {
-                        self.builders[&region]
-                    } else {
-                        // if region.flash is None:
-                        //     raise RuntimeError("flash memory region at address 0x%08x has no flash instance" % address)
-                        self.builders[&region] = FlashBuilder::new(Flash::new(target, region, FlashAlgorithm::new()));
-                        self.builders[&region]
+                    let mut builder = match self.builders.remove(&region) {
+                        Some(builder) => builder,
+                        None => self.create_builder(&region),
                     };
                 
                     // Add as much data to the builder as is contained by this region.
-                    let program_length = usize::min(remaining, (region.end() - address + 1) as usize);
-                    builder.add_data(address, data[size - remaining..program_length]);
+                    let offset = size - remaining;
+                    let program_length = u64::min(remaining as u64, region.end() - address) as usize;
+                    let result = builder.add_data(address, &data[offset..offset + program_length]);
+                    self.builders.insert(region.clone(), builder);
+                    result.map_err(FlashLoaderError::Builder)?;
+                    self.total_data_size += program_length;
                     
                     // Advance the cursors.
                     remaining -= program_length;
-                    address += program_length as u32;
+                    address += program_length as u64;
                 } else {
                     return Err(FlashLoaderError::MemoryRegionNotFlash(address));
                 }
@@ -418,8 +711,32 @@ impl<'a> FlashLoader<'a> {
         Ok(())
     }
     
+    /// Determine what `commit` would do without erasing or programming anything.
+    ///
+    /// The returned plan lists the sectors that would be erased and the pages that would be
+    /// programmed in every region, whether chip erase would be used and the estimated time.
+    /// The collected data is kept, so `commit` can be called afterwards.
+    pub fn plan(&mut self) -> Result<FlashPlan, FlashLoaderError> {
+        let mut did_chip_erase = false;
+        let mut plan = FlashPlan::default();
+
+        let protected_ranges = self.protected_ranges();
+        let mut builders: Vec<&mut FlashBuilder> = self.builders.values_mut().collect();
+        builders.sort_unstable_by_key(|v| v.flash_start);
+        for builder in builders {
+            builder.set_protected_ranges(protected_ranges.clone());
+            let chip_erase = if !did_chip_erase { self.chip_erase } else { Some(false) };
+            let region_plan = builder.plan(chip_erase, true).map_err(FlashLoaderError::Builder)?;
+            plan.estimated_time += region_plan.estimated_time;
+            plan.regions.push(region_plan);
+            did_chip_erase = true;
+        }
+
+        Ok(plan)
+    }
+
     /// Write all collected data to flash.
-        
+    ///
     /// This routine ensures that chip erase is only used once if either the auto mode or chip
     /// erase mode are used. As an example, if two regions are to be written to and True was
     /// passed to the constructor for chip_erase (or if the session option was set), then only
@@ -427,24 +744,182 @@ impl<'a> FlashLoader<'a> {
     /// sector erase. This will not result in extra erasing, as sector erase always verifies whether
     /// the sectors are already erased. This will, of course, also work correctly if the flash
     /// algorithm for the first region doesn't actually erase the entire chip (all regions).
-    
+    ///
     /// After calling this method, the loader instance can be reused to program more data.
-    pub fn commit(self) {
+    /// If the commit is cancelled, the regions which were not completely programmed are kept,
+    /// so the next commit programs them. With a journal, it resumes where it was cancelled.
+    ///
+    /// Returns the combined programming report of all regions.
+    /// Progress is reported to `progress` as one stream over all regions. All regions are
+    /// analyzed first, then each region gets a share according to its estimated programming time.
+    pub fn commit(&mut self, progress: &mut dyn ProgressObserver) -> Result<ProgrammingInfo, FlashLoaderError> {
         let mut did_chip_erase = false;
-        
+        let mut perf = ProgrammingInfo::default();
+
+        // Persist that a run is in progress, so an interrupted run leads to a validation of the cache.
+        if let Some(page_cache) = self.get_page_cache() {
+            page_cache.borrow_mut().begin_run();
+            self.save_page_cache()?;
+        }
+
         // Iterate over builders we've created and program the data.
-        let builders: Vec<&FlashBuilder> = self.builders.values().collect();
+        let protected_ranges = self.protected_ranges();
+        let mut builders: Vec<FlashBuilder> = self.builders.drain().map(|(_, builder)| builder).collect();
         builders.sort_unstable_by_key(|v| v.flash_start);
-        let sorted = builders;
-        for builder in sorted {
-            // Program the data.
-            let chip_erase = if !did_chip_erase { self.chip_erase } else { false };
-            builder.program(chip_erase, true);
+        let mut sorted = builders;
+
+        // Identify the image by its checksum, so only a run of the same data is resumed. The
+        // checksum is kept when a commit is cancelled, as the remaining builders are only a part
+        // of the image.
+        let journal = match &self.journal {
+            Some((path, device_id)) => {
+                let image_crc = *self.image_crc.get_or_insert_with(|| sorted.iter().fold(0, |crc, builder| builder.data_crc(crc)));
+                ProgrammingJournal::open(path, device_id, image_crc)
+                    .map(|journal| Some(Rc::new(RefCell::new(journal))))
+                    .map_err(FlashLoaderError::Journal)
+            },
+            None => Ok(None),
+        };
+        let journal = match journal {
+            Ok(journal) => journal,
+            Err(e) => return self.abort(sorted.into_iter(), e),
+        };
+
+        // Build the pages of all regions to estimate how long analyzing and programming takes.
+        for index in 0..sorted.len() {
+            let builder = &mut sorted[index];
+            builder.set_protected_ranges(protected_ranges.clone());
+            if let Some(token) = &self.cancellation_token {
+                builder.set_cancellation_token(token.clone());
+            }
+            if let Some(journal) = &journal {
+                builder.set_journal(journal.clone());
+            }
+            if let Err(e) = builder.build_pages() {
+                return self.abort(sorted.into_iter(), FlashLoaderError::Builder(e));
+            }
+        }
+
+        // Until the analysis is done, the programming time is estimated for erasing every page.
+        let analysis_time: f32 = sorted.iter().map(|builder| builder.estimated_analysis_time()).sum();
+        let program_time: f32 = sorted.iter().map(|builder| builder.estimated_program_time()).sum();
+        let analysis_share = share(analysis_time, analysis_time + program_time);
+
+        // Analyze all regions first, so the programming can be shared by the estimated times.
+        let token = self.cancellation_token.clone();
+        let is_cancelled = || token.as_ref().is_some_and(|token| token.is_cancelled());
+        let mut progress_offset = 0.0;
+        for index in 0..sorted.len() {
+            let builder = &mut sorted[index];
+            if is_cancelled() {
+                break;
+            }
+            let share = analysis_share * share(builder.estimated_analysis_time(), analysis_time);
+            let mut region_progress = ScaledProgress::new(progress, progress_offset, share);
+            progress_offset += share;
+
+            let chip_erase = if !did_chip_erase { self.chip_erase } else { Some(false) };
+            if let Err(e) = builder.analyze_data(chip_erase, true, &mut region_progress) {
+                // The error of the analysis is reported instead of one of the cleanup.
+                let _ = builder.cleanup();
+                return self.abort(sorted.into_iter(), FlashLoaderError::Builder(e));
+            }
             did_chip_erase = true;
         }
 
+        if is_cancelled() {
+            return self.cancel(sorted.into_iter(), perf);
+        }
+
+        // Program all regions, each with the share of its estimated time.
+        let program_time: f32 = sorted.iter().map(|builder| builder.estimated_program_time()).sum();
+        let mut progress_offset = analysis_share;
+        let mut pending = sorted.into_iter();
+        while let Some(mut builder) = pending.next() {
+            if is_cancelled() {
+                return self.cancel(std::iter::once(builder).chain(pending), perf);
+            }
+            let mut retry_policy = self.retry_policy.clone();
+            retry_policy.max_errors = retry_policy.max_errors.saturating_sub(perf.error_count);
+            builder.set_retry_policy(retry_policy);
+
+            // Give this region its share of the combined progress.
+            let share = (1.0 - analysis_share) * share(builder.estimated_program_time(), program_time);
+            let mut region_progress = ScaledProgress::new(progress, progress_offset, share);
+            progress_offset += share;
+
+            // The data of a cancelled or failed region has to be programmed by the next commit.
+            let region = builder.region().clone();
+            let mut resume = self.create_builder(&region);
+            builder.copy_data(&mut resume);
+
+            // Program the data as decided by the analysis.
+            let info = match builder.program(None, true, &mut region_progress) {
+                Ok(info) => info,
+                Err(FlashBuilderError::Cancelled(info)) => {
+                    perf.merge(&info);
+                    return self.cancel(std::iter::once(resume).chain(pending), perf);
+                },
+                // The builder cleaned up the flash of the region already.
+                Err(e) => return self.abort(std::iter::once(resume).chain(pending), FlashLoaderError::Builder(e)),
+            };
+            perf.merge(&info);
+        }
+
+        // The run completed, so there is nothing left to resume.
+        if let Some(journal) = journal {
+            let journal = Rc::try_unwrap(journal).map_err(|_| FlashLoaderError::Journal(JournalError::InUse))?;
+            journal.into_inner().finish().map_err(FlashLoaderError::Journal)?;
+        }
+
         // Clear state to allow reuse.
         self.reset_state();
+
+        self.save_calibration()?;
+
+        if let Some(page_cache) = self.get_page_cache() {
+            page_cache.borrow_mut().finish_run();
+            self.save_page_cache()?;
+        }
+
+        Ok(perf)
+    }
+
+    /// Keep the `builders` which were not programmed for the next commit, and persist what the
+    /// cancelled commit learned.
+    ///
+    /// The page cache stays marked as in use by a run, so it is validated before it is used again.
+    fn cancel(&mut self, builders: impl Iterator<Item = FlashBuilder>, perf: ProgrammingInfo) -> Result<ProgrammingInfo, FlashLoaderError> {
+        self.restore(builders);
+        self.save_calibration()?;
+        self.save_page_cache()?;
+        Err(FlashLoaderError::Cancelled(Box::new(perf)))
+    }
+
+    /// Keep the `builders` which were not programmed for the next commit and fail with `error`.
+    ///
+    /// The data can then be programmed by another commit, e.g. after changing the settings.
+    fn abort(&mut self, builders: impl Iterator<Item = FlashBuilder>, error: FlashLoaderError) -> Result<ProgrammingInfo, FlashLoaderError> {
+        self.restore(builders);
+        Err(error)
+    }
+
+    /// Put `builders` back into the loader.
+    fn restore(&mut self, builders: impl Iterator<Item = FlashBuilder>) {
+        self.total_data_size = 0;
+        for builder in builders {
+            self.total_data_size += builder.buffered_data_size;
+            self.builders.insert(builder.region().clone(), builder);
+        }
+    }
+}
+
+/// Fraction of `total` which `part` takes, or 0.0 if there is nothing to share.
+fn share(part: f32, total: f32) -> f32 {
+    if total > 0.0 {
+        part / total
+    } else {
+        0.0
     }
 }
 
@@ -481,4 +956,137 @@ fn ranges_works() {
             (7, 7),
         ]
     );
-}
\ No newline at end of file
+
+    let r = ranges(std::iter::empty());
+    assert_eq!(r.collect::<Vec<(usize, usize)>>(), vec![]);
+}
+#[test]
+fn hex_chunks_uses_extended_addresses() {
+    let hex = ":020000040800F2\n:04FFFE0001020304F5\n:020000040801F1\n:020002000506F1\n:020010000708DF\n:020000021000EC\n:0100000009F6\n:00000001FF\n";
+    assert_eq!(
+        hex_chunks(ihex::reader::Reader::new(hex)).unwrap(),
+        vec![
+            (0x0800_FFFE, vec![1, 2, 3, 4, 5, 6]),
+            (0x0801_0010, vec![7, 8]),
+            (0x0001_0000, vec![9]),
+        ]
+    );
+}
+
+#[test]
+fn commit_progress_is_monotonic() {
+    use crate::memory_map::MemoryRegion;
+    use crate::progress::ProgressPhase;
+
+    let memory_map = MemoryMap::new(vec![
+        MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None),
+        MemoryRegion::new(RegionType::Flash, 0x1000, 0x4000, 0x1000, None),
+    ]);
+    let mut loader = FlashLoader::new(memory_map, Rc::new(Target::new()));
+    loader.add_data(0x0, &[0x55; 0x800]).unwrap();
+    loader.add_data(0x1000, &[0xAA; 0x3000]).unwrap();
+
+    let mut reports = vec![];
+    loader.commit(&mut |phase, fraction| reports.push((phase, fraction))).unwrap();
+
+    assert_eq!(reports.first().map(|report| report.0), Some(ProgressPhase::Analyze));
+    assert!(reports.windows(2).all(|pair| pair[0].1 <= pair[1].1 + 1e-6));
+    assert!((reports.last().unwrap().1 - 1.0).abs() < 1e-3);
+}
+
+#[test]
+fn cancelled_commit_keeps_the_data() {
+    use crate::memory_map::MemoryRegion;
+    use crate::progress::ProgressPhase;
+
+    let memory_map = MemoryMap::new(vec![
+        MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None),
+        MemoryRegion::new(RegionType::Flash, 0x1000, 0x1000, 0x400, None),
+    ]);
+    let mut loader = FlashLoader::new(memory_map, Rc::new(Target::new()));
+    let token = CancellationToken::new();
+    loader.set_cancellation_token(token.clone());
+    loader.add_data(0x0, &[0x55; 0x800]).unwrap();
+    loader.add_data(0x1000, &[0xAA; 0x800]).unwrap();
+
+    // Cancel as soon as the first region is programmed.
+    let cancel = token.clone();
+    let result = loader.commit(&mut |phase, _| if phase == ProgressPhase::Program { cancel.cancel() });
+    match result {
+        Err(FlashLoaderError::Cancelled(_)) => (),
+        _ => panic!("the commit was not cancelled"),
+    }
+    assert_eq!(loader.builders.len(), 2);
+    assert_eq!(loader.total_data_size, 0x1000);
+
+    loader.set_cancellation_token(CancellationToken::new());
+    let info = loader.commit(&mut |_, _| ()).unwrap();
+    assert_eq!(info.programmed_pages.len(), 4);
+    assert!(loader.builders.is_empty());
+}
+
+#[test]
+fn failed_commit_keeps_the_data() {
+    use crate::memory_map::MemoryRegion;
+
+    let mut memory_map = MemoryMap::new(vec![
+        MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None),
+        MemoryRegion::new(RegionType::Flash, 0x1000, 0x1000, 0x400, None),
+    ]);
+    memory_map.add_protected_range(0x1000..0x1400);
+    let mut loader = FlashLoader::new(memory_map, Rc::new(Target::new()));
+    loader.add_data(0x0, &[0x55; 0x800]).unwrap();
+    loader.add_data(0x1000, &[0xAA; 0x800]).unwrap();
+
+    match loader.commit(&mut |_, _| ()) {
+        Err(FlashLoaderError::Builder(FlashBuilderError::ProtectedRange(0x1000))) => (),
+        _ => panic!("the protected range was programmed"),
+    }
+    assert_eq!(loader.builders.len(), 2);
+    assert_eq!(loader.total_data_size, 0x1000);
+
+    loader.set_override_protection(true);
+    let info = loader.commit(&mut |_, _| ()).unwrap();
+    assert_eq!(info.programmed_pages.len(), 4);
+}
+
+#[test]
+fn family_cost_model_decides_the_erase() {
+    use crate::cost::{
+        CalibratedCostModel,
+        CostModel,
+    };
+    use crate::memory_map::MemoryRegion;
+
+    // Chip erase takes much longer than erasing every page.
+    struct SlowChipErase;
+
+    impl CostModel for SlowChipErase {
+        fn page_erase_weight(&self, _address: Address, _size: u32) -> f32 { 0.001 }
+        fn page_program_weight(&self, _address: Address, _size: u32) -> f32 { 0.001 }
+        fn chip_erase_weight(&self, _size: u64) -> f32 { 1000.0 }
+        fn data_transfer_rate(&self) -> f32 { CalibratedCostModel::DEFAULT_DATA_TRANSFER_B_PER_S }
+    }
+
+    struct Family;
+
+    impl TargetFamily for Family {
+        fn cost_model(&self, _region: &MemoryRegion) -> Option<Box<dyn CostModel>> {
+            Some(Box::new(SlowChipErase))
+        }
+    }
+
+    let new_loader = || {
+        let memory_map = MemoryMap::new(vec![MemoryRegion::new(RegionType::Flash, 0x0, 0x20000, 0x400, None)]);
+        FlashLoader::new(memory_map, Rc::new(Target::new()))
+    };
+
+    let mut loader = new_loader();
+    loader.add_data(0x8000, &[0xFF; 0x10000]).unwrap();
+    assert!(loader.plan().unwrap().regions[0].chip_erase);
+
+    let mut loader = new_loader();
+    loader.set_target_family(Box::new(Family));
+    loader.add_data(0x8000, &[0xFF; 0x10000]).unwrap();
+    assert!(!loader.plan().unwrap().regions[0].chip_erase);
+}
@@ -1,52 +1,136 @@
+use std::ops::Range;
 use crate::flash_algorithm::FlashAlgorithm;
 
+/// Address in the physical address map of the target.
+///
+/// Addresses are 64 bits wide, so flash mapped above 4 GiB can be programmed.
+pub type Address = u64;
+
 pub struct MemoryMap {
     regions: Vec<MemoryRegion>,
+    protected_ranges: Vec<Range<Address>>,
 }
 
 impl MemoryMap {
     pub fn new(regions: Vec<MemoryRegion>) -> Self {
         Self {
-            regions: regions,
+            regions,
+            protected_ranges: vec![],
         }
     }
+
+    /// Protect an address range from being erased or programmed.
+    ///
+    /// Use this for bootloaders, factory calibration data or key stores which must survive
+    /// flashing. The FlashLoader refuses to touch protected ranges unless it is forced to.
+    pub fn add_protected_range(&mut self, range: Range<Address>) {
+        self.protected_ranges.push(range);
+    }
+
+    pub fn get_protected_ranges(&self) -> &[Range<Address>] {
+        &self.protected_ranges
+    }
 }
 
 impl MemoryMap {
-    pub fn get_region_for_address(&self, address: u32) -> Option<MemoryRegion> {
-        for r in self.regions {
+    pub fn get_region_for_address(&self, address: Address) -> Option<MemoryRegion> {
+        for r in &self.regions {
             if r.contains_address(address) {
-                return Some(r);
+                return Some(r.clone());
             }
         }
         None
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct MemoryRegion {
     pub(crate) typ: RegionType,
-    pub(crate) start: u32,
-    pub(crate) length: u32,
+    pub(crate) start: Address,
+    pub(crate) length: u64,
     pub(crate) blocksize: u32,
     pub(crate) algorithm: Option<FlashAlgorithm>,
+    pub(crate) erased_byte_value: u8,
+    pub(crate) program_without_erase: bool,
 }
 
 impl MemoryRegion {
-    const erased_byte_value: u8 = 0x00;
+    /// Value assumed for erased memory if the region has no flash algorithm.
+    const DEFAULT_ERASED_BYTE_VALUE: u8 = 0xFF;
+
+    /// Create a new memory region.
+    ///
+    /// The erased byte value is taken from the flash algorithm if there is one.
+    pub fn new(typ: RegionType, start: Address, length: u64, blocksize: u32, algorithm: Option<FlashAlgorithm>) -> Self {
+        let erased_byte_value = algorithm
+            .as_ref()
+            .map_or(Self::DEFAULT_ERASED_BYTE_VALUE, |a| a.get_erased_byte_value());
+        Self {
+            typ,
+            start,
+            length,
+            blocksize,
+            algorithm,
+            erased_byte_value,
+            program_without_erase: false,
+        }
+    }
 
-    pub fn end(&self) -> u32 {
-        self.start + self.length
+    /// Override the value erased memory of this region reads as.
+    ///
+    /// This is needed for flashes with ECC which erase to other patterns than the flash algorithm reports.
+    pub fn set_erased_byte_value(&mut self, value: u8) {
+        self.erased_byte_value = value;
     }
 
-    pub fn contains_address(&self, address: u32) -> bool {
-        (address >= self.start) && (address <= self.end())
+    pub fn erased_byte_value(&self) -> u8 {
+        self.erased_byte_value
+    }
+
+    /// Allow programming over already programmed data without an erase.
+    ///
+    /// NOR flash can move bits away from their erased state without an erase, so a page that
+    /// only needs additional bits programmed can be written directly. This saves erase time and
+    /// wear for append-only areas. Do not enable this for controllers that forbid programming
+    /// an ECC word twice.
+    pub fn set_program_without_erase(&mut self, enable: bool) {
+        self.program_without_erase = enable;
+    }
+
+    /// First address after the region.
+    ///
+    /// A region which reaches the end of the address space ends at the last address instead.
+    pub fn end(&self) -> Address {
+        self.start.saturating_add(self.length)
+    }
+
+    pub fn contains_address(&self, address: Address) -> bool {
+        address >= self.start && address - self.start < self.length
+    }
+
+    pub fn contains_range(&self, range: &Range<Address>) -> bool {
+        range.start >= self.start && range.start <= range.end && range.end - self.start <= self.length
     }
 
     /// Helper method to check if a block of data is erased.
-    pub fn is_erased(self, d: &[u8]) -> bool {
+    pub fn is_erased(&self, d: &[u8]) -> bool {
         for b in d {
-            if *b != Self::erased_byte_value {
+            if *b != self.erased_byte_value {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Helper method to check if `new` can be programmed over `old` without an erase.
+    ///
+    /// This is the case if every bit which is programmed in `old` is also programmed in `new`.
+    pub fn can_program_over(&self, old: &[u8], new: &[u8]) -> bool {
+        if old.len() != new.len() {
+            return false;
+        }
+        for (o, n) in old.iter().zip(new) {
+            if (o ^ self.erased_byte_value) & !(n ^ self.erased_byte_value) != 0 {
                 return false;
             }
         }
@@ -54,11 +138,53 @@ impl MemoryRegion {
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RegionType {
     Other,
     Ram,
     Rom,
     Flash,
     Device,
-}
\ No newline at end of file
+}
+#[test]
+fn is_erased_uses_region_erased_byte_value() {
+    let mut region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    assert!(region.is_erased(&[0xFF, 0xFF, 0xFF, 0xFF]));
+    assert!(!region.is_erased(&[0xFF, 0x00, 0xFF, 0xFF]));
+
+    region.set_erased_byte_value(0x00);
+    assert!(region.is_erased(&[0x00, 0x00, 0x00, 0x00]));
+    assert!(!region.is_erased(&[0xFF, 0xFF, 0xFF, 0xFF]));
+}
+
+#[test]
+fn erased_byte_value_comes_from_algorithm() {
+    let mut algorithm = FlashAlgorithm::new();
+    algorithm.set_erased_byte_value(0x00);
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, Some(algorithm));
+    assert_eq!(region.erased_byte_value(), 0x00);
+}
+
+#[test]
+fn can_program_over_only_programs_bits() {
+    let mut region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    assert!(region.can_program_over(&[0xFF, 0xF0], &[0x0F, 0x00]));
+    assert!(!region.can_program_over(&[0x0F, 0xF0], &[0xFF, 0xF0]));
+
+    region.set_erased_byte_value(0x00);
+    assert!(region.can_program_over(&[0x0F, 0x00], &[0xFF, 0xF0]));
+    assert!(!region.can_program_over(&[0xFF, 0xF0], &[0x0F, 0xF0]));
+}
+
+#[test]
+fn region_bounds_do_not_wrap() {
+    let qspi = MemoryRegion::new(RegionType::Flash, 0x1_0000_0000, 0x100_0000, 0x1000, None);
+    assert!(qspi.contains_address(0x1_0000_0000));
+    assert!(!qspi.contains_address(0x1_0100_0000));
+    assert!(qspi.contains_range(&(0x1_00FF_F000..0x1_0100_0000)));
+
+    let top = MemoryRegion::new(RegionType::Flash, 0xFFFF_FFFF_FFFF_F000, 0x1000, 0x400, None);
+    assert!(top.contains_address(0xFFFF_FFFF_FFFF_FFFF));
+    assert!(!top.contains_address(0x0));
+    assert_eq!(top.end(), Address::MAX);
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    BufReader,
+    BufWriter,
+    ErrorKind,
+};
+use std::ops::Range;
+use std::path::Path;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use crate::memory_map::Address;
+
+/// Checksum of a page as it was last programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedPage {
+    pub size: u32,
+    /// CRC32 of the page data, padded with the erased byte value to the page size.
+    pub crc: u32,
+}
+
+/// Host side record of the pages last programmed on one device.
+///
+/// With a trusted cache the FlashBuilder skips pages whose new data has the same checksum as
+/// the cached page without reading anything from the target. Every `validation_interval` runs,
+/// or when requested, the cached checksums are validated against the target and the cache is
+/// cleared if any of them do not match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageHashCache {
+    pub version: u32,
+    pub device_id: String,
+    /// Runs since the cache was last validated.
+    runs_since_validation: u32,
+    /// A run was started but did not finish, so the cache may not match the target.
+    in_progress: bool,
+    pages: BTreeMap<Address, CachedPage>,
+    #[serde(skip)]
+    validation_interval: u32,
+    #[serde(skip)]
+    validation_requested: bool,
+}
+
+#[derive(Debug)]
+pub enum PageCacheError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+}
+
+impl fmt::Display for PageCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageCacheError::Io(_) => write!(f, "could not access the page hash cache"),
+            PageCacheError::Format(_) => write!(f, "the page hash cache is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for PageCacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PageCacheError::Io(e) => Some(e),
+            PageCacheError::Format(e) => Some(e),
+        }
+    }
+}
+
+impl PageHashCache {
+    pub const VERSION: u32 = 1;
+    pub const DEFAULT_VALIDATION_INTERVAL: u32 = 50;
+
+    pub fn new(device_id: &str) -> Self {
+        Self {
+            version: Self::VERSION,
+            device_id: device_id.to_owned(),
+            runs_since_validation: 0,
+            in_progress: false,
+            pages: BTreeMap::new(),
+            validation_interval: Self::DEFAULT_VALIDATION_INTERVAL,
+            validation_requested: false,
+        }
+    }
+
+    /// Load the cache of the device with unique ID `device_id` from `path`.
+    ///
+    /// Returns an empty cache if the file does not exist, has a different version or belongs to
+    /// another device.
+    pub fn load(path: &Path, device_id: &str) -> Result<Self, PageCacheError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new(device_id)),
+            Err(e) => return Err(PageCacheError::Io(e)),
+        };
+        let mut cache: Self = serde_json::from_reader(BufReader::new(file)).map_err(PageCacheError::Format)?;
+        if cache.version != Self::VERSION || cache.device_id != device_id {
+            return Ok(Self::new(device_id));
+        }
+        cache.validation_interval = Self::DEFAULT_VALIDATION_INTERVAL;
+        cache.validation_requested = cache.in_progress;
+        Ok(cache)
+    }
+
+    /// Store the cache at `path`.
+    pub fn save(&self, path: &Path) -> Result<(), PageCacheError> {
+        let file = File::create(path).map_err(PageCacheError::Io)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).map_err(PageCacheError::Format)
+    }
+
+    /// Validate the cache against the target every `interval` runs.
+    pub fn set_validation_interval(&mut self, interval: u32) {
+        self.validation_interval = interval;
+    }
+
+    /// Validate the cache against the target on the next run.
+    pub fn request_validation(&mut self) {
+        self.validation_requested = true;
+    }
+
+    /// Check if the cache has to be validated before it is used in this run.
+    pub fn needs_validation(&self) -> bool {
+        self.validation_requested || self.runs_since_validation >= self.validation_interval
+    }
+
+    /// Mark the start of a programming run.
+    ///
+    /// The cache should be saved afterwards, so an interrupted run is detected on the next load.
+    pub fn begin_run(&mut self) {
+        self.in_progress = true;
+    }
+
+    /// Mark the end of a programming run.
+    ///
+    /// If the cache needed validation, it was validated during this run.
+    pub fn finish_run(&mut self) {
+        self.in_progress = false;
+        if self.needs_validation() {
+            self.runs_since_validation = 0;
+            self.validation_requested = false;
+        } else {
+            self.runs_since_validation += 1;
+        }
+    }
+
+    /// Check if the page at `address` was last programmed with data that has the checksum `crc`.
+    pub fn is_unchanged(&self, address: Address, size: u32, crc: u32) -> bool {
+        self.pages.get(&address) == Some(&CachedPage { size, crc })
+    }
+
+    /// All cached pages which start in `range`.
+    pub fn pages_in(&self, range: Range<Address>) -> impl Iterator<Item = (&Address, &CachedPage)> {
+        self.pages.range(range)
+    }
+
+    /// Record that the page at `address` now holds data with the checksum `crc`.
+    pub fn insert(&mut self, address: Address, size: u32, crc: u32) {
+        self.pages.insert(address, CachedPage { size, crc });
+    }
+
+    /// Forget all pages which start in `range`.
+    pub fn invalidate(&mut self, range: Range<Address>) {
+        let addresses: Vec<Address> = self.pages.range(range).map(|(address, _)| *address).collect();
+        for address in addresses {
+            self.pages.remove(&address);
+        }
+    }
+
+    /// Forget all pages.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+#[test]
+fn page_hash_cache_validation_schedule() {
+    let mut cache = PageHashCache::new("0123456789");
+    cache.set_validation_interval(2);
+    assert!(!cache.needs_validation());
+    cache.finish_run();
+    cache.finish_run();
+    assert!(cache.needs_validation());
+    cache.finish_run();
+    assert!(!cache.needs_validation());
+
+    cache.request_validation();
+    assert!(cache.needs_validation());
+    cache.finish_run();
+    assert!(!cache.needs_validation());
+}
@@ -0,0 +1,89 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use crate::memory_map::Address;
+
+/// A range of flash that would be erased or programmed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedSector {
+    pub address: Address,
+    pub size: u64,
+    /// The contents could not be compared up front. The sector is only written if it differs.
+    pub unverified: bool,
+}
+
+impl PlannedSector {
+    pub fn new(address: Address, size: u64, unverified: bool) -> Self {
+        Self {
+            address,
+            size,
+            unverified,
+        }
+    }
+
+    /// Check if the sector overlaps the range from `start` up to but not including `end`.
+    pub fn overlaps(&self, start: Address, end: Address) -> bool {
+        self.address < end && start < self.address.saturating_add(self.size)
+    }
+}
+
+/// The erase and program operations a FlashBuilder would perform on its region.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionPlan {
+    pub region_start: Address,
+    pub region_end: Address,
+    pub chip_erase: bool,
+    pub erased_sectors: Vec<PlannedSector>,
+    pub programmed_pages: Vec<PlannedSector>,
+    /// Estimated time in seconds.
+    pub estimated_time: f32,
+}
+
+impl RegionPlan {
+    pub fn new(region_start: Address, region_end: Address, chip_erase: bool) -> Self {
+        Self {
+            region_start,
+            region_end,
+            chip_erase,
+            erased_sectors: vec![],
+            programmed_pages: vec![],
+            estimated_time: 0.0,
+        }
+    }
+}
+
+/// The erase and program operations a FlashLoader would perform on commit.
+///
+/// Created by `FlashLoader::plan` without erasing or programming anything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FlashPlan {
+    pub regions: Vec<RegionPlan>,
+    /// Estimated time in seconds.
+    pub estimated_time: f32,
+}
+
+impl FlashPlan {
+    /// Check if committing would erase or program anything from `start` up to but not including `end`.
+    pub fn touches(&self, start: Address, end: Address) -> bool {
+        self.regions.iter().any(|region| {
+            region.erased_sectors.iter().any(|sector| sector.overlaps(start, end))
+                || region.programmed_pages.iter().any(|page| page.overlaps(start, end))
+        })
+    }
+}
+
+#[test]
+fn plan_touches_overlapping_ranges_only() {
+    let mut region = RegionPlan::new(0x0, 0x10000, false);
+    region.erased_sectors.push(PlannedSector::new(0x4000, 0x400, false));
+    region.programmed_pages.push(PlannedSector::new(0x4000, 0x100, false));
+    let plan = FlashPlan {
+        regions: vec![region],
+        estimated_time: 0.1,
+    };
+
+    assert!(!plan.touches(0x0, 0x4000));
+    assert!(plan.touches(0x43FF, 0x4400));
+    assert!(!plan.touches(0x4400, 0x8000));
+}
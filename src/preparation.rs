@@ -0,0 +1,215 @@
+use crate::flash::FlashError;
+use crate::target::Target;
+
+/// Changes to the target which flashing needs and which are undone afterwards.
+///
+/// Implementations are per target family. They disable watchdogs, raise clocks, unlock the
+/// flash controller or disable caches, and remember what they changed so `restore` can put
+/// it back.
+pub trait TargetPreparation {
+    /// Prepare the target before the flash algorithm is loaded.
+    fn prepare(&mut self, target: &Target) -> Result<(), FlashError>;
+
+    /// Undo the changes of `prepare` after flashing.
+    ///
+    /// This is also called if `prepare` failed, to undo the changes it made before the failure.
+    fn restore(&mut self, target: &Target) -> Result<(), FlashError>;
+
+    /// Read the core clock in Hz from the clock configuration of the prepared target.
+    ///
+    /// Returns `None` if the clock can not be determined.
+    fn core_clock(&self, _target: &Target) -> Option<u32> {
+        None
+    }
+}
+
+/// Leaves the target as it is, for families whose flash algorithms do everything themselves.
+pub struct NoPreparation;
+
+impl TargetPreparation for NoPreparation {
+    fn prepare(&mut self, _target: &Target) -> Result<(), FlashError> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _target: &Target) -> Result<(), FlashError> {
+        Ok(())
+    }
+}
+
+/// Preparation of the STM32F4 family.
+///
+/// The watchdogs are frozen while the core is halted, the flash caches are disabled and the
+/// flash controller is unlocked. The caches are reset before they are enabled again, so they
+/// hold no stale flash contents.
+///
+/// The core clock is read from the RCC. If it is derived from the HSE, its frequency has to be
+/// set with `set_hse_frequency`, as it depends on the board.
+#[derive(Default)]
+pub struct Stm32f4Preparation {
+    saved_acr: Option<u32>,
+    saved_freeze: Option<u32>,
+    was_locked: bool,
+    hse_frequency: Option<u32>,
+}
+
+impl Stm32f4Preparation {
+    const FLASH_ACR: u32 = 0x4002_3C00;
+    const FLASH_KEYR: u32 = 0x4002_3C04;
+    const FLASH_CR: u32 = 0x4002_3C10;
+    const DBGMCU_APB1_FZ: u32 = 0xE004_2008;
+    const RCC_PLLCFGR: u32 = 0x4002_3804;
+    const RCC_CFGR: u32 = 0x4002_3808;
+    const HSI_FREQUENCY: u32 = 16_000_000;
+
+    const ACR_ICEN: u32 = 1 << 9;
+    const ACR_DCEN: u32 = 1 << 10;
+    const ACR_ICRST: u32 = 1 << 11;
+    const ACR_DCRST: u32 = 1 << 12;
+    const CR_LOCK: u32 = 1 << 31;
+    const FZ_WWDG_STOP: u32 = 1 << 11;
+    const FZ_IWDG_STOP: u32 = 1 << 12;
+    const KEYS: [u32; 2] = [0x4567_0123, 0xCDEF_89AB];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the frequency of the external oscillator of the board in Hz.
+    pub fn set_hse_frequency(&mut self, hz: u32) {
+        self.hse_frequency = Some(hz);
+    }
+
+    /// Compute the core clock from the values of RCC_CFGR and RCC_PLLCFGR.
+    fn decode_core_clock(&self, cfgr: u32, pllcfgr: u32) -> Option<u32> {
+        let system_clock = match (cfgr >> 2) & 0b11 {
+            0 => Self::HSI_FREQUENCY as u64,
+            1 => self.hse_frequency? as u64,
+            2 => {
+                let input = if pllcfgr & 1 << 22 != 0 { self.hse_frequency? } else { Self::HSI_FREQUENCY };
+                let m = pllcfgr & 0x3F;
+                let n = (pllcfgr >> 6) & 0x1FF;
+                let p = (((pllcfgr >> 16) & 0b11) + 1) * 2;
+                if m == 0 {
+                    return None;
+                }
+                input as u64 * n as u64 / m as u64 / p as u64
+            },
+            _ => return None,
+        };
+        let ahb_divider = match (cfgr >> 4) & 0xF {
+            0..=7 => 1,
+            8 => 2,
+            9 => 4,
+            10 => 8,
+            11 => 16,
+            12 => 64,
+            13 => 128,
+            14 => 256,
+            _ => 512,
+        };
+        Some((system_clock / ahb_divider) as u32)
+    }
+
+    fn read(target: &Target, address: u32) -> u32 {
+        target.read_memory_block32(address.into(), 1)[0]
+    }
+
+    fn write(target: &Target, address: u32, value: u32) {
+        target.write_memory_block32(address.into(), &[value]);
+    }
+}
+
+impl TargetPreparation for Stm32f4Preparation {
+    fn prepare(&mut self, target: &Target) -> Result<(), FlashError> {
+        let freeze = Self::read(target, Self::DBGMCU_APB1_FZ);
+        Self::write(target, Self::DBGMCU_APB1_FZ, freeze | Self::FZ_WWDG_STOP | Self::FZ_IWDG_STOP);
+        self.saved_freeze = Some(freeze);
+
+        let acr = Self::read(target, Self::FLASH_ACR);
+        Self::write(target, Self::FLASH_ACR, acr & !(Self::ACR_ICEN | Self::ACR_DCEN));
+        self.saved_acr = Some(acr);
+
+        self.was_locked = Self::read(target, Self::FLASH_CR) & Self::CR_LOCK != 0;
+        if self.was_locked {
+            for key in &Self::KEYS {
+                Self::write(target, Self::FLASH_KEYR, *key);
+            }
+            if Self::read(target, Self::FLASH_CR) & Self::CR_LOCK != 0 {
+                return Err(FlashError::TargetPreparation("the flash controller did not unlock".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, target: &Target) -> Result<(), FlashError> {
+        if self.was_locked {
+            let cr = Self::read(target, Self::FLASH_CR);
+            Self::write(target, Self::FLASH_CR, cr | Self::CR_LOCK);
+            self.was_locked = false;
+        }
+
+        if let Some(acr) = self.saved_acr.take() {
+            // The caches can only be reset while they are disabled.
+            let disabled = acr & !(Self::ACR_ICEN | Self::ACR_DCEN);
+            Self::write(target, Self::FLASH_ACR, disabled | Self::ACR_ICRST | Self::ACR_DCRST);
+            Self::write(target, Self::FLASH_ACR, disabled);
+            Self::write(target, Self::FLASH_ACR, acr);
+        }
+
+        if let Some(freeze) = self.saved_freeze.take() {
+            Self::write(target, Self::DBGMCU_APB1_FZ, freeze);
+        }
+        Ok(())
+    }
+
+    fn core_clock(&self, target: &Target) -> Option<u32> {
+        self.decode_core_clock(Self::read(target, Self::RCC_CFGR), Self::read(target, Self::RCC_PLLCFGR))
+    }
+}
+
+#[test]
+fn stm32f4_core_clock_from_rcc() {
+    let mut preparation = Stm32f4Preparation::new();
+    // HSI directly, AHB divided by 2
+    assert_eq!(preparation.decode_core_clock(0b1000 << 4, 0), Some(8_000_000));
+    // PLL from HSE: 8 MHz / 8 * 336 / 2 = 168 MHz
+    let pllcfgr = 1 << 22 | 336 << 6 | 8;
+    assert_eq!(preparation.decode_core_clock(0b10 << 2, pllcfgr), None);
+    preparation.set_hse_frequency(8_000_000);
+    assert_eq!(preparation.decode_core_clock(0b10 << 2, pllcfgr), Some(168_000_000));
+}
+
+#[test]
+fn failed_preparation_is_restored() {
+    use crate::flash::{
+        Flash,
+        FlashOperation,
+    };
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct FailingPreparation(Rc<Cell<u32>>);
+
+    impl TargetPreparation for FailingPreparation {
+        fn prepare(&mut self, _target: &Target) -> Result<(), FlashError> {
+            Err(FlashError::TargetPreparation("failed".to_owned()))
+        }
+
+        fn restore(&mut self, _target: &Target) -> Result<(), FlashError> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    let restored = Rc::new(Cell::new(0));
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    let mut flash = Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new());
+    flash.set_target_preparation(Box::new(FailingPreparation(restored.clone())));
+    assert!(flash.init(FlashOperation::Program).is_err());
+    assert_eq!(restored.get(), 1);
+}
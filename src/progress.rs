@@ -0,0 +1,93 @@
+/// Phase of a flash programming run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPhase {
+    /// Flash contents are compared against the new data.
+    Analyze,
+    /// Pages or the whole chip are erased.
+    Erase,
+    /// Pages are programmed.
+    Program,
+    /// Pages are read back to check whether they need programming.
+    Verify,
+}
+
+/// Receives progress reports of a programming run.
+///
+/// `fraction` is the progress of the whole run, going from 0.0 to 1.0. It is weighted by the
+/// estimated time of every operation, so it can be shown as a single progress bar.
+///
+/// Closures of the form `|phase, fraction| { ... }` can be used as observers directly.
+pub trait ProgressObserver {
+    fn progress(&mut self, phase: ProgressPhase, fraction: f32);
+}
+
+impl<F: FnMut(ProgressPhase, f32)> ProgressObserver for F {
+    fn progress(&mut self, phase: ProgressPhase, fraction: f32) {
+        self(phase, fraction)
+    }
+}
+
+/// Maps the progress of a part of a run onto its share of the progress of the whole run.
+///
+/// This is used by the FlashLoader to combine the reports of multiple FlashBuilders.
+pub(crate) struct ScaledProgress<'a> {
+    observer: &'a mut dyn ProgressObserver,
+    offset: f32,
+    scale: f32,
+}
+
+impl<'a> ScaledProgress<'a> {
+    pub fn new(observer: &'a mut dyn ProgressObserver, offset: f32, scale: f32) -> Self {
+        Self {
+            observer,
+            offset,
+            scale,
+        }
+    }
+}
+
+impl<'a> ProgressObserver for ScaledProgress<'a> {
+    fn progress(&mut self, phase: ProgressPhase, fraction: f32) {
+        self.observer.progress(phase, self.offset + fraction * self.scale);
+    }
+}
+
+/// Tracks the weight of completed operations against the estimated total weight of a run.
+pub(crate) struct ProgressTracker {
+    done: f32,
+    total: f32,
+}
+
+impl ProgressTracker {
+    pub fn new(total: f32) -> Self {
+        Self {
+            done: 0.0,
+            total,
+        }
+    }
+
+    /// Add the weight of a completed operation and report the new progress.
+    pub fn advance(&mut self, observer: &mut dyn ProgressObserver, phase: ProgressPhase, weight: f32) {
+        self.done += weight;
+        let fraction = if self.total > 0.0 {
+            f32::min(self.done / self.total, 1.0)
+        } else {
+            1.0
+        };
+        observer.progress(phase, fraction);
+    }
+}
+
+#[test]
+fn scaled_progress_maps_into_share() {
+    let mut reports = vec![];
+    {
+        let mut observer = |_phase, fraction| reports.push(fraction);
+        let mut scaled = ScaledProgress::new(&mut observer, 0.25, 0.5);
+        let mut tracker = ProgressTracker::new(2.0);
+        tracker.advance(&mut scaled, ProgressPhase::Program, 1.0);
+        tracker.advance(&mut scaled, ProgressPhase::Program, 1.0);
+        tracker.advance(&mut scaled, ProgressPhase::Program, 1.0);
+    }
+    assert_eq!(reports, vec![0.5, 0.75, 0.75]);
+}
@@ -0,0 +1,140 @@
+use crate::flash::{
+    Flash,
+    FlashError,
+    FlashOperation,
+};
+use crate::memory_map::Address;
+
+/// How failed page erase and program operations are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of times a failed page operation is retried.
+    pub page_retries: u32,
+    /// Uninit and init the flash algorithm again before retrying.
+    pub reinit_algorithm: bool,
+    /// Reset the target and download the flash algorithm again before retrying.
+    pub reset_target: bool,
+    /// Number of failed operations after which programming is aborted, including failures
+    /// which succeeded when retried.
+    pub max_errors: usize,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT_MAX_ERRORS: usize = 10;
+
+    /// A policy which does not retry.
+    pub fn new() -> Self {
+        Self {
+            page_retries: 0,
+            reinit_algorithm: false,
+            reset_target: false,
+            max_errors: Self::DEFAULT_MAX_ERRORS,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a page operation on an algorithm initialized for `operation`, retrying it according to `policy`.
+///
+/// The page at `page_address` is erased again before a failed program is retried.
+/// Every failure is added to `error_count`. Returns the number of retries that were needed.
+pub(crate) fn retry_page_operation<F>(
+    flash: &mut Flash,
+    policy: &RetryPolicy,
+    error_count: &mut usize,
+    operation: FlashOperation,
+    page_address: Address,
+    mut page_operation: F
+) -> Result<u32, FlashError>
+where
+    F: FnMut(&mut Flash) -> Result<(), FlashError>
+{
+    let mut retries = 0;
+    loop {
+        match page_operation(flash) {
+            Ok(()) => return Ok(retries),
+            Err(error) => {
+                *error_count += 1;
+                if retries >= policy.page_retries || *error_count > policy.max_errors {
+                    return Err(error);
+                }
+                retries += 1;
+
+                if policy.reset_target {
+                    flash.reset_and_reload();
+                    flash.init(operation)?;
+                } else if policy.reinit_algorithm {
+                    // The algorithm is in an unknown state, so a failing uninit is expected.
+                    let _ = flash.uninit();
+                    flash.init(operation)?;
+                }
+
+                if operation == FlashOperation::Program {
+                    // The failed program may have left the page partially programmed.
+                    flash.uninit()?;
+                    flash.init(FlashOperation::Erase)?;
+                    flash.erase_page(page_address)?;
+                    flash.uninit()?;
+                    flash.init(FlashOperation::Program)?;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn failed_program_is_retried_on_an_erased_page() {
+    use crate::cost::{
+        CostModel,
+        CostOperation,
+    };
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::memory_map::{
+        MemoryRegion,
+        RegionType,
+    };
+    use crate::target::Target;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    struct RecordingCostModel(Rc<RefCell<Vec<CostOperation>>>);
+
+    impl CostModel for RecordingCostModel {
+        fn page_erase_weight(&self, _address: Address, _size: u32) -> f32 { 0.0 }
+        fn page_program_weight(&self, _address: Address, _size: u32) -> f32 { 0.0 }
+        fn chip_erase_weight(&self, _size: u64) -> f32 { 0.0 }
+        fn data_transfer_rate(&self) -> f32 { 1.0 }
+        fn record(&mut self, operation: CostOperation, _size: u64, _duration: Duration) {
+            self.0.borrow_mut().push(operation);
+        }
+    }
+
+    let region = MemoryRegion::new(RegionType::Flash, 0x0, 0x1000, 0x400, None);
+    let mut flash = Flash::new(Rc::new(Target::new()), region, FlashAlgorithm::new());
+    let operations = Rc::new(RefCell::new(vec![]));
+    flash.set_cost_model(Box::new(RecordingCostModel(operations.clone())));
+
+    let mut policy = RetryPolicy::new();
+    policy.page_retries = 2;
+    let mut error_count = 0;
+    let mut attempts = 0;
+    flash.init(FlashOperation::Program).unwrap();
+    let retries = retry_page_operation(&mut flash, &policy, &mut error_count, FlashOperation::Program, 0x400, |_| {
+        attempts += 1;
+        if attempts == 1 {
+            Err(FlashError::WrongOperationOngoing(FlashOperation::Program))
+        } else {
+            Ok(())
+        }
+    }).unwrap();
+
+    assert_eq!(retries, 1);
+    assert_eq!(error_count, 1);
+    assert_eq!(*operations.borrow(), vec![CostOperation::PageErase]);
+}
@@ -0,0 +1,90 @@
+use crate::memory_map::Address;
+
+/// What to do with data which would lock the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockingPolicy {
+    /// Refuse to program the data with `FlashError::LockingValue`.
+    #[default]
+    Refuse,
+    /// Patch the data, so the device stays unlocked.
+    Unlock,
+    /// Program the data as it is. The device may become inaccessible for good.
+    Allow,
+}
+
+/// Configuration values of a family which can lock the device, e.g. against debug access.
+pub trait SecurityBits {
+    /// Find the address of the first value in `data` which would lock the device.
+    ///
+    /// `data` is about to be programmed at `address`.
+    fn find_locking_value(&self, address: Address, data: &[u8]) -> Option<Address>;
+
+    /// Patch `data`, which is about to be programmed at `address`, so it does not lock the device.
+    fn unlock(&self, address: Address, data: &mut [u8]);
+}
+
+/// For families without values which lock the device.
+pub struct NoSecurityBits;
+
+impl SecurityBits for NoSecurityBits {
+    fn find_locking_value(&self, _address: Address, _data: &[u8]) -> Option<Address> {
+        None
+    }
+
+    fn unlock(&self, _address: Address, _data: &mut [u8]) {}
+}
+
+/// Flash configuration field of the Kinetis family.
+///
+/// The FSEC byte at 0x40C secures the device unless its SEC bits are 0b10. If its MEEN bits are
+/// 0b10, mass erase is disabled and a secured device can never be recovered.
+pub struct KinetisSecurityBits;
+
+impl KinetisSecurityBits {
+    const FSEC_ADDRESS: Address = 0x40C;
+    const FSEC_UNSECURE: u8 = 0xFE; // Backdoor key disabled, mass erase enabled, unsecure
+
+    /// Offset of the FSEC byte in `data` at `address`, if it contains it.
+    fn fsec_offset(address: Address, data: &[u8]) -> Option<usize> {
+        if address <= Self::FSEC_ADDRESS && Self::FSEC_ADDRESS - address < data.len() as u64 {
+            Some((Self::FSEC_ADDRESS - address) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl SecurityBits for KinetisSecurityBits {
+    fn find_locking_value(&self, address: Address, data: &[u8]) -> Option<Address> {
+        let fsec = data[Self::fsec_offset(address, data)?];
+        let secured = fsec & 0b11 != 0b10;
+        let mass_erase_disabled = (fsec >> 4) & 0b11 == 0b10;
+        if secured || mass_erase_disabled {
+            Some(Self::FSEC_ADDRESS)
+        } else {
+            None
+        }
+    }
+
+    fn unlock(&self, address: Address, data: &mut [u8]) {
+        if let Some(offset) = Self::fsec_offset(address, data) {
+            data[offset] = Self::FSEC_UNSECURE;
+        }
+    }
+}
+
+#[test]
+fn kinetis_fsec_is_checked_and_patched() {
+    let mut data = vec![0xFF; 0x10];
+    data[0x0C] = 0xFE;
+    assert_eq!(KinetisSecurityBits.find_locking_value(0x400, &data), None);
+    assert_eq!(KinetisSecurityBits.find_locking_value(0x410, &data), None);
+
+    data[0x0C] = 0xFF;
+    assert_eq!(KinetisSecurityBits.find_locking_value(0x400, &data), Some(0x40C));
+    KinetisSecurityBits.unlock(0x400, &mut data);
+    assert_eq!(data[0x0C], 0xFE);
+
+    data[0x0C] = 0xEE;
+    assert_eq!(KinetisSecurityBits.find_locking_value(0x400, &data), Some(0x40C));
+}
@@ -1,3 +1,116 @@
+use crate::core_register::CoreRegister;
+use crate::memory_map::Address;
+
+/// Run state of the core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    Running,
+    Halted,
+}
+
+#[derive(Default)]
 pub struct Target {
-    
-}
\ No newline at end of file
+
+}
+
+impl Target {
+    /// Vector catch mask which halts the core on every exception.
+    pub const CATCH_ALL: u32 = 0xFFFF_FFFF;
+
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn get_state(&self) -> TargetState {
+        // TODO: Read through the probe once there is one.
+        TargetState::Halted
+    }
+
+    pub fn halt(&self) {
+        // TODO: Halt through the probe once there is one.
+    }
+
+    pub fn resume(&self) {
+        // TODO: Resume through the probe once there is one.
+    }
+
+    /// Reset the target and halt it before it executes the first instruction.
+    pub fn reset_stop_on_reset(&self) {
+        // TODO: Reset through the probe once there is one.
+    }
+
+    /// Get the mask of the exceptions the core halts on.
+    pub fn get_vector_catch(&self) -> u32 {
+        // TODO: Read through the probe once there is one.
+        0
+    }
+
+    /// Halt the core on the exceptions in `mask`.
+    pub fn set_vector_catch(&self, mask: u32) {
+        // TODO: Write through the probe once there is one.
+        let _ = mask;
+    }
+
+    /// Read `size` bytes starting at `address`.
+    pub fn read_memory_block8(&self, address: Address, size: u32) -> Vec<u8> {
+        // TODO: Read through the probe once there is one.
+        let _ = address;
+        vec![0; size as usize]
+    }
+
+    /// Write `data` starting at `address`.
+    pub fn write_memory_block8(&self, address: Address, data: &[u8]) {
+        // TODO: Write through the probe once there is one.
+        let _ = (address, data);
+    }
+
+    /// Read `count` words starting at `address`.
+    pub fn read_memory_block32(&self, address: Address, count: u32) -> Vec<u32> {
+        // TODO: Read through the probe once there is one.
+        let _ = address;
+        vec![0; count as usize]
+    }
+
+    /// Write the words in `data` starting at `address`.
+    pub fn write_memory_block32(&self, address: Address, data: &[u32]) {
+        // TODO: Write through the probe once there is one.
+        let _ = (address, data);
+    }
+
+    /// Read the registers with the debug interface IDs `ids`.
+    pub fn read_core_registers_raw(&self, ids: &[u16]) -> Vec<u32> {
+        // TODO: Read through the probe once there is one.
+        vec![0; ids.len()]
+    }
+
+    /// Write `values` to the registers with the debug interface IDs `ids`.
+    pub fn write_core_registers_raw(&self, ids: &[u16], values: &[u32]) {
+        // TODO: Write through the probe once there is one.
+        let _ = (ids, values);
+    }
+
+    /// Read several core registers in one batch.
+    ///
+    /// The values are returned in the order of `registers`.
+    pub fn read_core_registers<R: CoreRegister>(&self, registers: &[R]) -> Vec<u32> {
+        let ids: Vec<u16> = registers.iter().map(|register| register.id()).collect();
+        self.read_core_registers_raw(ids.as_slice())
+    }
+
+    /// Write several core registers in one batch.
+    pub fn write_core_registers<R: CoreRegister>(&self, registers: &[(R, u32)]) {
+        let (ids, values): (Vec<u16>, Vec<u32>) = registers
+            .iter()
+            .map(|(register, value)| (register.id(), *value))
+            .unzip();
+        self.write_core_registers_raw(ids.as_slice(), values.as_slice());
+    }
+
+    pub fn read_core_register<R: CoreRegister>(&self, register: R) -> u32 {
+        self.read_core_registers(&[register])[0]
+    }
+
+    pub fn write_core_register<R: CoreRegister>(&self, register: R, value: u32) {
+        self.write_core_registers(&[(register, value)]);
+    }
+}